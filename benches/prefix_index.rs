@@ -0,0 +1,99 @@
+// Run with `cargo bench --bench prefix_index`.
+//
+// The binary crate has no library target, so the module is included directly.  Its unit tests
+// are left out of benchmarks, which leaves their imports unused when built as a test.
+#[path = "../src/prefix_index.rs"]
+#[cfg_attr(test, allow(unused_imports))]
+mod prefix_index;
+
+use prefix_index::{Lookup, PrefixIndex};
+use sha1::{Digest, Sha1};
+use std::time::{Duration, Instant};
+
+const NUM_TASKS: usize = 100_000;
+const NUM_UPDATES: usize = 1_000;
+
+fn task_id(n: usize) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(n.to_string());
+    format!("{:x}", hasher.finalize())
+}
+
+fn report(name: &str, count: usize, elapsed: Duration) {
+    println!(
+        "{:<24} {:>8} in {:>10.3?} ({:>8.3?} each)",
+        name,
+        count,
+        elapsed,
+        elapsed / count as u32
+    );
+}
+
+fn main() {
+    let ids: Vec<String> = (0..NUM_TASKS).map(task_id).collect();
+
+    let start = Instant::now();
+    let mut index = PrefixIndex::new(ids.iter().map(|id| (id.as_str(), false)));
+    report("build", NUM_TASKS, start.elapsed());
+
+    let start = Instant::now();
+    let mut found = 0;
+    for id in &ids {
+        let prefix = index.prefix(id).unwrap();
+        if let Lookup::Unique(full_id) = index.lookup(prefix) {
+            assert_eq!(full_id, id);
+            found += 1;
+        }
+    }
+    report("prefix + lookup", NUM_TASKS, start.elapsed());
+    assert_eq!(found, NUM_TASKS);
+
+    let start = Instant::now();
+    let mut ambiguous = 0;
+    for id in &ids[..NUM_UPDATES] {
+        if let Lookup::Ambiguous(matches) = index.lookup(&id[..1]) {
+            assert!(matches.iter().all(|m| m.starts_with(&id[..1])));
+            ambiguous += 1;
+        }
+    }
+    report("ambiguous lookup", NUM_UPDATES, start.elapsed());
+    assert_eq!(ambiguous, NUM_UPDATES);
+
+    let new_ids: Vec<String> = (NUM_TASKS..NUM_TASKS + NUM_UPDATES).map(task_id).collect();
+    let start = Instant::now();
+    for id in &new_ids {
+        index.insert(id, false);
+    }
+    report("insert", NUM_UPDATES, start.elapsed());
+
+    let start = Instant::now();
+    for id in &new_ids {
+        index.remove(id);
+    }
+    report("remove", NUM_UPDATES, start.elapsed());
+    assert!(index.prefix(&new_ids[0]).is_none());
+
+    let start = Instant::now();
+    for id in &ids {
+        index.issue(id);
+    }
+    report("issue stable", NUM_TASKS, start.elapsed());
+
+    let start = Instant::now();
+    let mut reloaded = PrefixIndex::new(ids.iter().map(|id| (id.as_str(), false)));
+    for id in &ids {
+        reloaded.set_stable(id, &[index.prefix(id).unwrap().to_string()]);
+    }
+    report("load stable", NUM_TASKS, start.elapsed());
+
+    // Stable prefixes keep finding their ids as ids are added
+    for id in &new_ids {
+        reloaded.insert(id, false);
+    }
+    let start = Instant::now();
+    for id in &ids[..NUM_UPDATES] {
+        let prefix = reloaded.prefix(id).unwrap().to_string();
+        assert!(matches!(reloaded.lookup(&prefix), Lookup::Unique(full_id) if full_id == id));
+    }
+    report("stable lookup", NUM_UPDATES, start.elapsed());
+}
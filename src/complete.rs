@@ -0,0 +1,232 @@
+use super::task_list::TaskList;
+use clap::{App, Shell};
+use std::collections::BTreeSet;
+
+// Names and aliases of the subcommands with arguments that take `value`, e.g. ID for task ids or
+// TAG for tags, going by their value names such as ID|TAG.
+fn subcommands_taking(app: &App, value: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    for subcommand in &app.p.subcommands {
+        let takes_value = subcommand
+            .p
+            .positionals
+            .values()
+            .flat_map(|positional| positional.v.val_names.iter().flat_map(|names| names.values()))
+            .any(|name| name.split('|').any(|part| part == value));
+        if takes_value {
+            names.push(subcommand.p.meta.name.to_string());
+            let aliases = subcommand.p.meta.aliases.iter().flatten();
+            names.extend(aliases.map(|(alias, _)| alias.to_string()));
+        }
+    }
+    names.sort();
+    names
+}
+
+// Options, of any subcommand, whose value is not a task id.
+fn value_options(app: &App) -> Vec<String> {
+    let mut options = BTreeSet::new();
+    let mut apps = vec![app];
+    while let Some(app) = apps.pop() {
+        for option in &app.p.opts {
+            let mut names = option.v.val_names.iter().flat_map(|names| names.values());
+            if let Some(long) =
+                option.s.long.filter(|_| !names.any(|name| *name == "ID" || *name == "IDS"))
+            {
+                options.insert(format!("--{}", long));
+            }
+        }
+        apps.extend(&app.p.subcommands);
+    }
+    options.into_iter().collect()
+}
+
+// Print the candidates for `t __complete`: the prefix and description of every task, separated by
+// a tab, or every tag in use.
+pub fn print_candidates(tasks: &TaskList, what: &str) {
+    match what {
+        "tags" => {
+            let tags: BTreeSet<&String> = tasks.tasks().iter().flat_map(|t| t.tags()).collect();
+            for tag in tags {
+                println!("{}", tag);
+            }
+        }
+        _ => {
+            for task in tasks.tasks() {
+                let prefix = tasks.prefix(task.id()).unwrap_or(task.id());
+                println!("{}\t{}", prefix, task.desc());
+            }
+        }
+    }
+}
+
+// Add shell code completing task ids and tags with `t __complete` to a script generated by clap
+// from `app`.
+// Where a command can only have one completion function, the new function is registered instead
+// and falls back to the generated one.
+pub fn add_dynamic_completions(app: &App, shell: Shell, script: &str) -> String {
+    let ids = subcommands_taking(app, "ID").join(" ");
+    let tags = subcommands_taking(app, "TAG").join(" ");
+    let options = value_options(app).join(" ");
+
+    match shell {
+        Shell::Bash => format!(
+            "{}{}",
+            script.replace("complete -F _t -o bashdefault -o default t", ""),
+            BASH.replace("{ids}", &ids.replace(' ', "|"))
+                .replace("{tags}", &tags.replace(' ', "|"))
+                .replace("{options}", &options.replace(' ', "|"))
+        ),
+        Shell::Zsh => format!(
+            "{}{}",
+            script.replace("_t \"$@\"", ""),
+            ZSH.replace("{ids}", &ids.replace(' ', "|"))
+                .replace("{tags}", &tags.replace(' ', "|"))
+                .replace("{options}", &options.replace(' ', "|"))
+        ),
+        Shell::Fish => format!(
+            "{}{}",
+            script,
+            FISH.replace("{ids}", &ids).replace("{tags}", &tags).replace("{options}", &options)
+        ),
+        _ => script.to_string(),
+    }
+}
+
+const BASH: &str = r#"
+_t_tasks() {
+    local cur prev sub i
+    local -a files candidates
+    cur="${COMP_WORDS[COMP_CWORD]}"
+    prev="${COMP_WORDS[COMP_CWORD-1]}"
+
+    for ((i = 1; i < COMP_CWORD; i++)); do
+        case "${COMP_WORDS[i]}" in
+            --file) files+=(--file "${COMP_WORDS[i+1]}"); ((i++)) ;;
+            {options}) ((i++)) ;;
+            -*) ;;
+            *) [[ -z "$sub" ]] && sub="${COMP_WORDS[i]}" ;;
+        esac
+    done
+
+    case "$prev" in
+        --parent) ;;
+        {options}) _t; return ;;
+        *)
+            if [[ "$sub" != @({ids}) || "$cur" == --* ]]; then
+                _t
+                return
+            fi
+            ;;
+    esac
+
+    if [[ "$prev" != --parent && "$sub" == @({tags}) && "$cur" == [+-]* ]]; then
+        candidates=($("${COMP_WORDS[0]}" __complete tags "${files[@]}" 2>/dev/null))
+        COMPREPLY=($(compgen -P "${cur:0:1}" -W "${candidates[*]}" -- "${cur:1}"))
+        return
+    fi
+
+    candidates=($("${COMP_WORDS[0]}" __complete ids "${files[@]}" 2>/dev/null | cut -f1))
+    if [[ "$prev" != --parent && "$sub" == @({tags}) ]]; then
+        candidates+=($("${COMP_WORDS[0]}" __complete tags "${files[@]}" 2>/dev/null))
+    fi
+    COMPREPLY=($(compgen -W "${candidates[*]}" -- "$cur"))
+}
+
+complete -F _t_tasks -o bashdefault -o default t
+"#;
+
+const ZSH: &str = r#"
+_t_tasks() {
+    local -a files candidates
+    local sub i
+    for ((i = 2; i < CURRENT; i++)); do
+        case ${words[i]} in
+            --file) files+=(--file ${words[i+1]}); ((i++)) ;;
+            {options}) ((i++)) ;;
+            -*) ;;
+            *) [[ -z $sub ]] && sub=${words[i]} ;;
+        esac
+    done
+
+    local prev=${words[CURRENT-1]} cur=${words[CURRENT]}
+    if [[ $prev != --parent ]]; then
+        if [[ $prev == ({options}) || $sub != ({ids}) || $cur == --* ]]; then
+            _t "$@"
+            return
+        fi
+    fi
+
+    if [[ $prev != --parent && $sub == ({tags}) && $cur == [+-]* ]]; then
+        candidates=(${(f)"$(${words[1]} __complete tags $files 2>/dev/null)"})
+        compadd -P ${cur[1]} -- $candidates
+        return
+    fi
+
+    candidates=(${(f)"$(${words[1]} __complete ids $files 2>/dev/null | tr '\t' ':')"})
+    _describe 'task' candidates
+    if [[ $prev != --parent && $sub == ({tags}) ]]; then
+        candidates=(${(f)"$(${words[1]} __complete tags $files 2>/dev/null)"})
+        compadd -- $candidates
+    fi
+}
+
+_t_tasks "$@"
+"#;
+
+const FISH: &str = r#"
+function __t_tasks
+    set -l words (commandline -opc)
+    set -l files
+    for i in (seq 2 (count $words))
+        if test "$words[$i]" = --file; and set -q words[(math $i + 1)]
+            set -a files --file $words[(math $i + 1)]
+        end
+    end
+    $words[1] __complete $argv $files 2>/dev/null
+end
+
+function __t_needs_task
+    set -l prev (commandline -opc)[-1]
+    not contains -- $prev {options}; and not string match -q -- '-*' (commandline -ct)
+end
+
+complete -c t -n "__fish_seen_subcommand_from {ids}; and __t_needs_task" -f -a "(__t_tasks ids)"
+complete -c t -n "__fish_seen_subcommand_from {tags}; and __t_needs_task" -f -a "(__t_tasks tags)"
+complete -c t -n "__fish_seen_subcommand_from {tags}; and string match -q -- '[+-]*' (commandline -ct)" -f -a "(string sub -l 1 -- (commandline -ct))(__t_tasks tags)"
+complete -c t -n "__fish_seen_subcommand_from a add" -l parent -x -a "(__t_tasks ids)"
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::get_args;
+
+    #[test]
+    fn completions_follow_the_command_line_definition() {
+        let app = get_args();
+        let ids = subcommands_taking(&app, "ID");
+        for name in ["complete", "c", "copy", "edit", "rename-id", "show", "tag", "t", "unfold"] {
+            assert!(ids.contains(&name.to_string()), "{}", name);
+        }
+        for name in ["add", "a", "list", "template", "diff"] {
+            assert!(!ids.contains(&name.to_string()), "{}", name);
+        }
+        assert_eq!(subcommands_taking(&app, "TAG"), ["t", "tag"]);
+
+        let options = value_options(&app);
+        for option in ["--backend", "--color", "--depth", "--file", "--from", "--id", "--where"] {
+            assert!(options.contains(&option.to_string()), "{}", option);
+        }
+        for option in ["--parent", "--ids", "--force"] {
+            assert!(!options.contains(&option.to_string()), "{}", option);
+        }
+
+        for shell in [Shell::Bash, Shell::Zsh, Shell::Fish] {
+            let script = add_dynamic_completions(&app, shell, "");
+            for placeholder in ["{ids}", "{tags}", "{options}"] {
+                assert!(!script.contains(placeholder), "{}", placeholder);
+            }
+        }
+    }
+}
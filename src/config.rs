@@ -0,0 +1,79 @@
+use super::task_list::TaskListError;
+use colored::Color;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+// Settings for everyone using a task file, read from `.t/config` next to it.  One setting per
+// line:
+//
+//   # comment
+//   template = {indent} {checkmark} {prefix}: {tags}{desc} {progress}
+//   color.urgent = bright red
+//   prefixes = stable
+#[derive(Default)]
+pub struct Config {
+    // Row format, see `template::Template`
+    pub template: Option<String>,
+    // Tasks keep the prefix they are first shown with, rather than the shortest unique one
+    pub stable_prefixes: bool,
+    // Colors of tags, by tag, other tags are red
+    pub tag_colors: HashMap<String, Color>,
+}
+
+// The directory next to `file` holding its config.
+pub fn dir(file: &str) -> PathBuf {
+    Path::new(file).parent().unwrap_or(Path::new("")).join(".t")
+}
+
+// Read the config of `file`, a missing config file is an empty config.
+pub fn load(file: &str) -> Result<Config, TaskListError> {
+    let mut config = Config::default();
+    let text = match fs::read_to_string(dir(file).join("config")) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(config),
+        Err(e) => return Err(e.into()),
+    };
+
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => {
+                return Err(TaskListError::BadConfig(format!(
+                    "expected 'setting = value', found '{}'",
+                    line
+                )))
+            }
+        };
+
+        if key == "template" {
+            config.template = Some(value.to_string());
+        } else if key == "prefixes" {
+            config.stable_prefixes = match value {
+                "stable" => true,
+                "shortest" => false,
+                _ => {
+                    return Err(TaskListError::BadConfig(format!(
+                        "prefixes must be 'stable' or 'shortest', not '{}'",
+                        value
+                    )))
+                }
+            };
+        } else if let Some(tag) = key.strip_prefix("color.") {
+            let color = Color::from_str(value)
+                .map_err(|_| TaskListError::BadConfig(format!("unknown color '{}'", value)))?;
+            config.tag_colors.insert(tag.to_string(), color);
+        } else {
+            return Err(TaskListError::BadConfig(format!("unknown setting '{}'", key)));
+        }
+    }
+
+    Ok(config)
+}
@@ -0,0 +1,252 @@
+use super::task;
+use super::task_list::TaskList;
+use colored::Colorize;
+use serde::Serialize;
+use std::collections::BTreeSet;
+
+#[derive(Serialize)]
+#[serde(tag = "change", rename_all = "lowercase")]
+enum Kind {
+    Added,
+    Removed,
+    Completed,
+    Uncompleted,
+    Edited { old_desc: String },
+    Tagged { added: Vec<String>, removed: Vec<String> },
+    Moved { old_parent_id: Option<String>, new_parent_id: Option<String> },
+    Scheduled { old_due: Option<String>, new_due: Option<String> },
+    Prioritized { old_priority: Option<task::Priority>, new_priority: Option<task::Priority> },
+}
+
+#[derive(Serialize)]
+pub struct Change {
+    id: String,
+    prefix: String,
+    desc: String,
+    #[serde(flatten)]
+    kind: Kind,
+}
+
+#[derive(Serialize)]
+pub struct Diff {
+    changes: Vec<Change>,
+}
+
+fn prefix_of(tasks: &TaskList, id: &str) -> String {
+    tasks.prefix(id).map_or(id, |prefix| prefix.as_str()).to_string()
+}
+
+// Describe a parent for the text output, e.g. '3f: Backend'.
+fn parent_name(tasks: &TaskList, parent_id: &Option<String>) -> String {
+    match parent_id.as_deref().and_then(|id| tasks.task_by_id(id)) {
+        Some(parent) => {
+            format!("{}: {}", prefix_of(tasks, parent.id()).yellow().bold(), parent.desc())
+        }
+        None => match parent_id {
+            Some(parent_id) => parent_id.to_string(),
+            None => "top level".to_string(),
+        },
+    }
+}
+
+fn task_changes(old: &task::Task, new: &task::Task) -> Vec<Kind> {
+    let mut kinds = Vec::new();
+
+    if old.is_completed() != new.is_completed() {
+        kinds.push(if new.is_completed() { Kind::Completed } else { Kind::Uncompleted });
+    }
+    if old.desc() != new.desc() {
+        kinds.push(Kind::Edited { old_desc: old.desc().to_string() });
+    }
+
+    let added: Vec<String> =
+        new.tags().iter().filter(|t| !old.tags().contains(t)).cloned().collect();
+    let removed: Vec<String> =
+        old.tags().iter().filter(|t| !new.tags().contains(t)).cloned().collect();
+    if !added.is_empty() || !removed.is_empty() {
+        kinds.push(Kind::Tagged { added, removed });
+    }
+
+    if old.due() != new.due() {
+        kinds.push(Kind::Scheduled {
+            old_due: old.due().map(String::from),
+            new_due: new.due().map(String::from),
+        });
+    }
+    if old.priority() != new.priority() {
+        kinds
+            .push(Kind::Prioritized { old_priority: old.priority(), new_priority: new.priority() });
+    }
+
+    if old.parent_id() != new.parent_id() {
+        kinds.push(Kind::Moved {
+            old_parent_id: old.parent_id().clone(),
+            new_parent_id: new.parent_id().clone(),
+        });
+    }
+
+    kinds
+}
+
+// Task level changes going from `old` to `new`, matching tasks by id.
+pub fn compute(old: &TaskList, new: &TaskList) -> Diff {
+    let ids: BTreeSet<&String> =
+        old.tasks().iter().chain(new.tasks().iter()).map(|task| task.id()).collect();
+
+    let mut changes = Vec::new();
+    for id in ids {
+        let change = |tasks: &TaskList, task: &task::Task, kind| Change {
+            id: id.to_string(),
+            prefix: prefix_of(tasks, id),
+            desc: task.desc().to_string(),
+            kind,
+        };
+
+        match (old.task_by_id(id), new.task_by_id(id)) {
+            (Some(old_task), Some(new_task)) => {
+                for kind in task_changes(old_task, new_task) {
+                    changes.push(change(new, new_task, kind));
+                }
+            }
+            (None, Some(new_task)) => changes.push(change(new, new_task, Kind::Added)),
+            (Some(old_task), None) => changes.push(change(old, old_task, Kind::Removed)),
+            (None, None) => {}
+        }
+    }
+
+    Diff { changes }
+}
+
+impl Diff {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap_or_default()
+    }
+
+    pub fn show(&self, old: &TaskList, new: &TaskList) {
+        for change in &self.changes {
+            let (marker, detail) = match &change.kind {
+                Kind::Added => ("+".green(), String::new()),
+                Kind::Removed => ("-".red(), String::new()),
+                Kind::Completed => ("~".yellow(), "completed".to_string()),
+                Kind::Uncompleted => ("~".yellow(), "uncompleted".to_string()),
+                Kind::Edited { old_desc } => ("~".yellow(), format!("was: {}", old_desc)),
+                Kind::Tagged { added, removed } => {
+                    let mut tags = Vec::new();
+                    tags.extend(added.iter().map(|tag| format!("+[{}]", tag.red())));
+                    tags.extend(removed.iter().map(|tag| format!("-[{}]", tag.red())));
+                    ("~".yellow(), format!("tags {}", tags.join(" ")))
+                }
+                Kind::Scheduled { old_due, new_due } => (
+                    "~".yellow(),
+                    format!(
+                        "due {}, was {}",
+                        new_due.as_deref().unwrap_or("-"),
+                        old_due.as_deref().unwrap_or("-")
+                    ),
+                ),
+                Kind::Prioritized { old_priority, new_priority } => (
+                    "~".yellow(),
+                    format!(
+                        "priority {}, was {}",
+                        new_priority.map_or("-", |priority| priority.name()),
+                        old_priority.map_or("-", |priority| priority.name())
+                    ),
+                ),
+                Kind::Moved { old_parent_id, new_parent_id } => (
+                    "~".yellow(),
+                    format!(
+                        "moved from {} to {}",
+                        parent_name(old, old_parent_id),
+                        parent_name(new, new_parent_id)
+                    ),
+                ),
+            };
+
+            if detail.is_empty() {
+                println!("{} {}: {}", marker, change.prefix.yellow().bold(), change.desc);
+            } else {
+                println!(
+                    "{} {}: {}  ({})",
+                    marker,
+                    change.prefix.yellow().bold(),
+                    change.desc,
+                    detail
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::temp_file;
+    use crate::task_list;
+
+    fn task(json: &str) -> task::Task {
+        task::create_from_file_string(&format!("Write docs | {}", json)).unwrap()
+    }
+
+    fn kinds(old: &str, new: &str) -> Vec<String> {
+        let kinds = task_changes(&task(old), &task(new));
+        kinds
+            .iter()
+            .map(|kind| serde_json::to_value(kind).unwrap()["change"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn unchanged_tasks_have_no_changes() {
+        assert!(kinds(r#"{"id":"a","tags":["x"]}"#, r#"{"id":"a","tags":["x"]}"#).is_empty());
+    }
+
+    #[test]
+    fn each_changed_field_is_a_change() {
+        let old = r#"{"id":"a","tags":["x"]}"#;
+        let new = r#"{"id":"a","tags":["y"],"parent_id":"p","due":"2024-05-01","priority":"low","completed_timestamp":5.0}"#;
+        assert_eq!(kinds(old, new), ["completed", "tagged", "scheduled", "prioritized", "moved"]);
+        assert_eq!(kinds(new, old), ["uncompleted", "tagged", "scheduled", "prioritized", "moved"]);
+    }
+
+    #[test]
+    fn edits_and_tags() {
+        let mut old = task(r#"{"id":"a","tags":["keep","drop"]}"#);
+        let mut new = old.clone();
+        new.set_desc("Write more docs");
+        new.set_tags(vec!["keep".to_string(), "add".to_string()]);
+        old.set_desc("Write docs");
+
+        let kinds = task_changes(&old, &new);
+        assert!(matches!(&kinds[0], Kind::Edited { old_desc } if old_desc == "Write docs"));
+        assert!(
+            matches!(&kinds[1], Kind::Tagged { added, removed } if added == &["add"] && removed == &["drop"])
+        );
+    }
+
+    #[test]
+    fn added_and_removed_tasks() {
+        let mut old = task_list::create_from_file(&temp_file("diff-old.t"), Some("text")).unwrap();
+        let mut new = task_list::create_from_file(&temp_file("diff-new.t"), Some("text")).unwrap();
+        old.set_quiet(true);
+        new.set_quiet(true);
+        old.add_task(None, Some("gone"), "Gone").unwrap();
+        old.add_task(None, Some("kept"), "Kept").unwrap();
+        new.upsert_task(old.task_by_id("kept").unwrap().clone());
+        new.add_task(None, Some("new"), "New").unwrap();
+        new.complete_task("kept", false).unwrap();
+
+        let diff = compute(&old, &new);
+        let changes: Vec<(&str, &str)> = diff
+            .changes
+            .iter()
+            .map(|change| match change.kind {
+                Kind::Added => (change.id.as_str(), "added"),
+                Kind::Removed => (change.id.as_str(), "removed"),
+                Kind::Completed => (change.id.as_str(), "completed"),
+                _ => (change.id.as_str(), "other"),
+            })
+            .collect();
+        assert_eq!(changes, [("gone", "removed"), ("kept", "completed"), ("new", "added")]);
+        assert_eq!(diff.changes[0].desc, "Gone");
+    }
+}
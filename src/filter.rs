@@ -0,0 +1,137 @@
+use super::task;
+use super::task_list::{TaskList, TaskListError};
+
+// A filter expression is a whitespace separated list of terms which must all match:
+//
+//   tag:NAME          task has tag NAME
+//   status:open       task is not completed
+//   status:completed  task is completed
+//   parent:ID         task is a direct sub-task of ID
+//   desc:TEXT / TEXT  description contains TEXT (case insensitive)
+//
+// Any term can be negated with a leading '!', e.g. '!tag:later'.
+#[derive(Clone)]
+enum Term {
+    Tag(String),
+    Completed(bool),
+    Parent(String),
+    Desc(String),
+}
+
+pub struct Filter {
+    terms: Vec<(bool, Term)>,
+}
+
+impl Filter {
+    pub fn parse(expr: &str) -> Result<Filter, TaskListError> {
+        let mut terms = Vec::new();
+
+        for word in expr.split_whitespace() {
+            let (negated, word) = match word.strip_prefix('!') {
+                Some(word) => (true, word),
+                None => (false, word),
+            };
+
+            let term = match word.split_once(':') {
+                Some(("tag", tag)) => Term::Tag(tag.to_string()),
+                Some(("status", "open")) => Term::Completed(false),
+                Some(("status", "completed")) | Some(("status", "done")) => Term::Completed(true),
+                Some(("parent", parent)) => Term::Parent(parent.to_string()),
+                Some(("desc", text)) => Term::Desc(text.to_lowercase()),
+                Some(_) => return Err(TaskListError::BadFilter(word.to_string())),
+                None => Term::Desc(word.to_lowercase()),
+            };
+
+            terms.push((negated, term));
+        }
+
+        Ok(Filter { terms })
+    }
+
+    // Full ids of all tasks matching the filter.
+    pub fn select(&self, tasks: &TaskList) -> Result<Vec<String>, TaskListError> {
+        // Resolve parent prefixes once up front
+        let mut terms = Vec::new();
+        for (negated, term) in &self.terms {
+            let term = match term {
+                Term::Parent(prefix) => Term::Parent(tasks.get_full_id(prefix)?),
+                term => term.clone(),
+            };
+            terms.push((*negated, term));
+        }
+        let filter = Filter { terms };
+
+        Ok(tasks
+            .tasks()
+            .iter()
+            .filter(|task| filter.matches(task))
+            .map(|task| task.id().to_string())
+            .collect())
+    }
+
+    // Note: parent terms must hold full ids, see `select`.
+    fn matches(&self, task: &task::Task) -> bool {
+        self.terms.iter().all(|(negated, term)| {
+            let matched = match term {
+                Term::Tag(tag) => task.tags().contains(tag),
+                Term::Completed(completed) => task.is_completed() == *completed,
+                Term::Parent(parent_id) => task.parent_id().as_ref() == Some(parent_id),
+                Term::Desc(text) => task.desc().to_lowercase().contains(text),
+            };
+            matched != *negated
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task(desc: &str, tags: &[&str]) -> task::Task {
+        let mut task = task::create(Some("parent"), None, desc);
+        for tag in tags {
+            task.add_tag(tag);
+        }
+        task
+    }
+
+    #[test]
+    fn all_terms_must_match() {
+        let filter = Filter::parse("tag:urgent status:open docs").unwrap();
+        assert!(filter.matches(&task("Write Docs", &["urgent"])));
+        assert!(!filter.matches(&task("Write Docs", &[])));
+        assert!(!filter.matches(&task("Write tests", &["urgent"])));
+
+        let mut completed = task("Write docs", &["urgent"]);
+        completed.set_complete(true);
+        assert!(!filter.matches(&completed));
+        assert!(Filter::parse("status:done").unwrap().matches(&completed));
+    }
+
+    #[test]
+    fn negated_terms() {
+        let filter = Filter::parse("!tag:later desc:call").unwrap();
+        assert!(filter.matches(&task("Call mom", &[])));
+        assert!(!filter.matches(&task("Call mom", &["later"])));
+    }
+
+    #[test]
+    fn parent_terms_match_full_ids() {
+        assert!(Filter::parse("parent:parent").unwrap().matches(&task("Sub-task", &[])));
+        assert!(!Filter::parse("parent:other").unwrap().matches(&task("Sub-task", &[])));
+    }
+
+    #[test]
+    fn empty_filter_matches_everything() {
+        assert!(Filter::parse("").unwrap().matches(&task("Anything", &[])));
+    }
+
+    #[test]
+    fn unknown_terms_are_rejected() {
+        assert!(matches!(
+            Filter::parse("owner:me"),
+            Err(TaskListError::BadFilter(term)) if term == "owner:me"
+        ));
+        assert!(Filter::parse("status:later").is_err());
+    }
+}
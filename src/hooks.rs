@@ -0,0 +1,135 @@
+use super::task;
+use super::task_list::TaskListError;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+// Executables in `.t/hooks` next to the task file, run before changed tasks are saved:
+//
+//   on-add       a task was added, gets the task
+//   on-complete  a task was completed, gets the task before and after
+//   on-modify    a task was otherwise changed, gets the task before and after
+//   on-remove    a task was removed, gets the task
+//
+// Each task is written to the hook's stdin as a line of JSON, description included.  If a hook
+// exits with an error nothing is saved.  Hooks run before anything is written, so they also run
+// for changes that are then lost because a task file can't be written.
+#[derive(Clone)]
+pub struct Hooks {
+    dir: PathBuf,
+    // Tasks as they were last saved, by id
+    saved: HashMap<String, task::Task>,
+}
+
+impl Hooks {
+    pub fn new(dir: PathBuf, tasks: &[task::Task]) -> Hooks {
+        let saved = tasks.iter().map(|task| (task.id().to_string(), task.clone())).collect();
+        Hooks { dir, saved }
+    }
+
+    // Run the hook for the change to task `id` since it was last saved, `task` is None if it was
+    // removed.
+    pub fn run(&self, id: &str, task: Option<&task::Task>) -> Result<(), TaskListError> {
+        match (self.saved.get(id), task) {
+            (None, Some(task)) => self.run_hook("on-add", &[task]),
+            (Some(old), Some(task)) if old == task => Ok(()),
+            (Some(old), Some(task)) if !old.is_completed() && task.is_completed() => {
+                self.run_hook("on-complete", &[old, task])
+            }
+            (Some(old), Some(task)) => self.run_hook("on-modify", &[old, task]),
+            (Some(old), None) => self.run_hook("on-remove", &[old]),
+            (None, None) => Ok(()),
+        }
+    }
+
+    // Record task `id` as saved, None if it was removed.
+    pub fn saved(&mut self, id: &str, task: Option<task::Task>) {
+        match task {
+            Some(task) => self.saved.insert(id.to_string(), task),
+            None => self.saved.remove(id),
+        };
+    }
+
+    fn run_hook(&self, name: &str, tasks: &[&task::Task]) -> Result<(), TaskListError> {
+        let path = self.dir.join(name);
+        if !path.is_file() {
+            return Ok(());
+        }
+        let failed = |reason: String| TaskListError::HookFailed(name.to_string(), reason);
+
+        let mut child =
+            Command::new(&path).stdin(Stdio::piped()).spawn().map_err(|e| failed(e.to_string()))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            for task in tasks {
+                // Hooks that don't read their input may exit before it is written
+                match writeln!(stdin, "{}", task.to_json_value()) {
+                    Err(e) if e.kind() == io::ErrorKind::BrokenPipe => break,
+                    result => result?,
+                }
+            }
+        }
+
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(failed(status.to_string()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::temp_file;
+    use crate::task_list;
+    use std::fs;
+
+    // All in one test: a hook written by one test could be held open by a process spawned by
+    // another, and then fail to run.
+    #[cfg(unix)]
+    #[test]
+    fn hooks_run_for_each_kind_of_change() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = PathBuf::from(temp_file("hooks"));
+        let _ = fs::remove_dir_all(&dir);
+        let hooks = dir.join(".t").join("hooks");
+        fs::create_dir_all(&hooks).unwrap();
+        let log = dir.join("log");
+        for name in ["on-add", "on-complete", "on-modify", "on-remove"] {
+            let script = format!("#!/bin/sh\necho {} $(wc -l) >> '{}'\n", name, log.display());
+            fs::write(hooks.join(name), script).unwrap();
+            fs::set_permissions(hooks.join(name), fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        let logged = || fs::read_to_string(&log).unwrap_or_default();
+
+        let file = dir.join("tasks.t").to_string_lossy().to_string();
+        let mut tasks = task_list::create_from_file(&file, Some("text")).unwrap();
+        tasks.set_quiet(true);
+        tasks.set_hooks(hooks.clone());
+
+        let a = tasks.add_task(None, None, "A").unwrap();
+        tasks.save().unwrap();
+        tasks.save().unwrap();
+        tasks.get_task(&a).unwrap().set_tags(vec!["x".to_string()]);
+        tasks.save().unwrap();
+        tasks.complete_task(&a, false).unwrap();
+        tasks.save().unwrap();
+        tasks.remove_task(&a, false).unwrap();
+        tasks.save().unwrap();
+        assert_eq!(logged(), "on-add 1\non-modify 2\non-complete 2\non-remove 1\n");
+
+        // A failing hook stops the save
+        fs::write(&log, "").unwrap();
+        fs::write(hooks.join("on-add"), "#!/bin/sh\nexit 1\n").unwrap();
+        tasks.add_task(None, None, "B").unwrap();
+        assert!(
+            matches!(tasks.save(), Err(TaskListError::HookFailed(name, _)) if name == "on-add")
+        );
+        let saved = task_list::create_from_file(&file, Some("text")).unwrap();
+        assert!(saved.tasks().is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
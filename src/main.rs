@@ -1,28 +1,64 @@
+mod complete;
+mod config;
+mod diff;
+mod filter;
+mod hooks;
+mod merge;
+mod prefix_index;
+mod serve;
+mod shell;
+mod stats;
+mod storage;
+mod sync;
 mod task;
 mod task_list;
+mod task_templates;
+mod template;
+mod ui;
 
 #[macro_use]
 extern crate clap;
-use clap::{App, AppSettings, Arg, ArgMatches, Shell, SubCommand};
+use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches, Shell, SubCommand};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, IsTerminal};
 use std::str::FromStr;
+use task_list::{TaskList, TaskListError};
+
+fn is_number(value: String) -> Result<(), String> {
+    match value.parse::<usize>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!("'{}' is not a number", value)),
+    }
+}
+
+fn is_port(value: String) -> Result<(), String> {
+    match value.parse::<u16>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!("'{}' is not a port number", value)),
+    }
+}
 
 fn get_args() -> clap::App<'static, 'static> {
     App::new("t")
-        .settings(&[AppSettings::DisableHelpSubcommand, AppSettings::VersionlessSubcommands])
+        .settings(&[
+            AppSettings::DisableHelpSubcommand,
+            AppSettings::VersionlessSubcommands,
+            AppSettings::SubcommandsNegateReqs,
+        ])
         .version(crate_version!())
         .author("Trent Lillehaugen <tllilleh@gmail.com>")
         .about("simple todo tracker")
+        .after_help(task_list::EXIT_STATUS)
         .subcommand(
             SubCommand::with_name("add")
                 .visible_alias("a")
                 .about("Create a new task")
-                .arg(
-                    Arg::with_name("id")
-                        .long("id")
-                        .value_name("ID")
-                        .takes_value(true)
-                        .help("Create task with ID, otherwise one will be auto generated"),
-                )
+                .arg(Arg::with_name("id").long("id").value_name("NEW_ID").takes_value(true).help(
+                    "Create task with NEW_ID, otherwise one will be auto generated.  NEW_ID may \
+                             only contain letters, digits, '_', '.' and '-', and neither it nor \
+                             any other task's id may be a prefix of the other.",
+                ))
                 .arg(
                     Arg::with_name("parent_id")
                         .long("parent")
@@ -30,62 +66,171 @@ fn get_args() -> clap::App<'static, 'static> {
                         .takes_value(true)
                         .help("Create task as a sub-task"),
                 )
+                .arg(
+                    Arg::with_name("from")
+                        .long("from")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .conflicts_with_all(&["id", "task"])
+                        .help(
+                            "Create one task per line of FILE, or stdin if FILE is '-'. Indented \
+                             lines become sub-tasks and #tags become tags.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("due")
+                        .long("due")
+                        .value_name("DATE")
+                        .takes_value(true)
+                        .help("Due date, as YYYY-MM-DD"),
+                )
+                .arg(
+                    Arg::with_name("priority")
+                        .long("priority")
+                        .value_name("LEVEL")
+                        .takes_value(true)
+                        .possible_values(&["high", "medium", "low"])
+                        .help("Priority"),
+                )
                 .arg(
                     Arg::with_name("task")
                         .value_name("DESC")
                         .help("Task description")
                         .multiple(true)
-                        .required(true),
+                        .required_unless("from"),
                 ),
         )
         .subcommand(
             SubCommand::with_name("remove")
                 .visible_alias("r")
-                .about("Remove a task")
+                .about("Remove tasks")
                 .arg(
                     Arg::with_name("id")
                         .value_name("ID")
+                        .multiple(true)
+                        .required_unless("where")
+                        .help("Task IDs to remove"),
+                )
+                .arg(
+                    Arg::with_name("where")
+                        .long("where")
+                        .value_name("FILTER")
                         .takes_value(true)
-                        .required(true)
-                        .help("Task ID to remove"),
+                        .help("Also select tasks matching FILTER, e.g. 'tag:sprint12 status:open'"),
                 )
                 .arg(
                     Arg::with_name("force")
                         .long("force")
                         .required(false)
-                        .help("Force remove a task if it has children"),
+                        .help("Force remove tasks that have children"),
                 ),
         )
         .subcommand(
             SubCommand::with_name("complete")
                 .visible_alias("c")
-                .about("Complete a task.  That is, check it off.")
+                .about("Complete tasks.  That is, check them off.")
                 .arg(
                     Arg::with_name("id")
                         .value_name("ID")
+                        .multiple(true)
+                        .required_unless("where")
+                        .help("Task IDs to complete"),
+                )
+                .arg(
+                    Arg::with_name("where")
+                        .long("where")
+                        .value_name("FILTER")
                         .takes_value(true)
-                        .required(true)
-                        .help("Task ID to complete"),
+                        .help("Also select tasks matching FILTER, e.g. 'tag:sprint12 status:open'"),
                 )
                 .arg(
                     Arg::with_name("force")
                         .long("force")
                         .required(false)
-                        .help("Force complete a task if it has children"),
+                        .help("Force complete tasks that have children"),
                 ),
         )
         .subcommand(
             SubCommand::with_name("uncomplete")
                 .visible_alias("u")
-                .about("Uncomplete a task. That is, uncheck it.")
+                .about("Uncomplete tasks. That is, uncheck them.")
                 .arg(
                     Arg::with_name("id")
                         .value_name("ID")
+                        .multiple(true)
+                        .required_unless("where")
+                        .help("Task IDs to uncomplete"),
+                )
+                .arg(
+                    Arg::with_name("where")
+                        .long("where")
+                        .value_name("FILTER")
                         .takes_value(true)
-                        .required(true)
-                        .help("Task ID to uncomplete"),
+                        .help("Also select tasks matching FILTER, e.g. 'tag:sprint12 status:open'"),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("copy")
+                .about("Copy a task and its sub-tasks")
+                .arg(Arg::with_name("id").value_name("ID").required(true).help("Task ID to copy"))
+                .arg(
+                    Arg::with_name("parent_id")
+                        .long("parent")
+                        .value_name("ID")
+                        .takes_value(true)
+                        .help("Add the copy under ID instead of next to the task"),
+                )
+                .arg(Arg::with_name("reset").long("reset").help("Leave the copies uncompleted")),
+        )
+        .subcommand(
+            SubCommand::with_name("template")
+                .about("Save tasks as named templates and add them again later")
+                .after_help(
+                    "Templates are kept in .t/templates next to FILE, for example:\n\n    \
+                     t template save 3f release\n    \
+                     t template use release --parent 8a",
+                )
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("save")
+                        .about("Save a task and its sub-tasks as a template")
+                        .arg(
+                            Arg::with_name("id")
+                                .value_name("ID")
+                                .required(true)
+                                .help("Task ID to save"),
+                        )
+                        .arg(
+                            Arg::with_name("name")
+                                .value_name("NAME")
+                                .required(true)
+                                .help("Template name, replacing any template with this name"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("use")
+                        .about("Add the tasks of a template")
+                        .arg(
+                            Arg::with_name("name")
+                                .value_name("NAME")
+                                .required(true)
+                                .help("Template name"),
+                        )
+                        .arg(
+                            Arg::with_name("parent_id")
+                                .long("parent")
+                                .value_name("ID")
+                                .takes_value(true)
+                                .help("Add the tasks as sub-tasks of ID"),
+                        )
+                        .arg(
+                            Arg::with_name("reset")
+                                .long("reset")
+                                .help("Leave the tasks uncompleted"),
+                        ),
+                )
+                .subcommand(SubCommand::with_name("list").about("List the saved templates")),
+        )
         .subcommand(
             SubCommand::with_name("edit")
                 .visible_alias("e")
@@ -97,33 +242,338 @@ fn get_args() -> clap::App<'static, 'static> {
                         .required(true)
                         .help("Task ID to edit"),
                 )
+                .arg(
+                    Arg::with_name("due")
+                        .long("due")
+                        .value_name("DATE")
+                        .takes_value(true)
+                        .help("Due date, as YYYY-MM-DD, or 'none' to clear it"),
+                )
+                .arg(
+                    Arg::with_name("priority")
+                        .long("priority")
+                        .value_name("LEVEL")
+                        .takes_value(true)
+                        .possible_values(&["high", "medium", "low", "none"])
+                        .help("Priority, or 'none' to clear it"),
+                )
                 .arg(
                     Arg::with_name("task")
                         .value_name("DESC")
-                        .help("Task description")
+                        .help("Task description, by default edited in $EDITOR unless only --due or \
+                               --priority are given")
                         .multiple(true)
                         .required(false),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("rename-id")
+                .about("Give a task a new id, its sub-tasks are updated to match")
+                .arg(Arg::with_name("id").value_name("ID").required(true).help("Task ID to rename"))
+                .arg(
+                    Arg::with_name("new_id")
+                        .value_name("NEW_ID")
+                        .required(true)
+                        .help("New id, with the same rules as 'add --id'"),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("tag")
                 .visible_alias("t")
-                .about("Tag/Untag a task")
+                .about("Tag/Untag tasks")
+                .setting(AppSettings::AllowLeadingHyphen)
+                .arg(
+                    Arg::with_name("ids")
+                        .long("ids")
+                        .value_name("IDS")
+                        .takes_value(true)
+                        .help("Tag these comma separated task IDs, e.g. 'a3,b7'"),
+                )
+                .arg(
+                    Arg::with_name("where")
+                        .long("where")
+                        .value_name("FILTER")
+                        .takes_value(true)
+                        .help("Tag tasks matching FILTER, e.g. 'tag:sprint12 status:open'"),
+                )
+                .arg(
+                    Arg::with_name("args")
+                        .value_name("ID|TAG")
+                        .help(
+                            "Task ID followed by tags, or only tags with --ids or --where.  TAG \
+                             or +TAG adds a tag and -TAG removes it.",
+                        )
+                        .multiple(true)
+                        .allow_hyphen_values(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("id-scheme")
+                .about("Show or change how ids are chosen for new tasks")
+                .after_help(
+                    "With 'sequential' new tasks in FILE are numbered #1, #2 and so on, and keep \
+                     their number for good.  Quote the ids on the command line, e.g. \
+                     t complete '#12'.  With 'hash' new tasks get hashes as ids, shown as the \
+                     shortest prefix that tells them apart.  Existing tasks keep their ids either \
+                     way.  Numbers are not coordinated between copies of FILE, so tasks added on \
+                     two branches get the same #N and always conflict when the branches are \
+                     merged.",
+                )
+                .arg(
+                    Arg::with_name("scheme")
+                        .value_name("SCHEME")
+                        .possible_values(&["hash", "sequential"])
+                        .help("Scheme to use from now on"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("List tasks, as a tree or one per line")
+                .arg(
+                    Arg::with_name("flat")
+                        .long("flat")
+                        .help("List one task per line, followed by the tasks it is under"),
+                )
+                .arg(
+                    Arg::with_name("where")
+                        .long("where")
+                        .value_name("FILTER")
+                        .takes_value(true)
+                        .help("Only list tasks matching FILTER, e.g. 'tag:sprint12 status:open'"),
+                )
+                .arg(
+                    Arg::with_name("hide-completed")
+                        .long("hide-completed")
+                        .help("Don't show completed tasks"),
+                )
+                .arg(
+                    Arg::with_name("sort")
+                        .long("sort")
+                        .value_name("FIELD")
+                        .takes_value(true)
+                        .possible_values(&["due", "priority", "created", "completed"])
+                        .requires("flat")
+                        .help(
+                            "Order of a flat list, by default created.  Tasks without a due date \
+                             or priority go last when sorting by those, and open tasks go last \
+                             when sorting by completed.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("group-by")
+                        .long("group-by")
+                        .value_name("FIELD")
+                        .takes_value(true)
+                        .possible_values(&["tag", "status", "parent"])
+                        .requires("flat")
+                        .help("Group a flat list by tag, status or parent task"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("show")
+                .about("Show the details of a task and its sub-tasks, or all tasks")
+                .arg(
+                    Arg::with_name("id").value_name("ID").takes_value(true).help("Task ID to show"),
+                )
+                .arg(
+                    Arg::with_name("depth")
+                        .long("depth")
+                        .value_name("N")
+                        .takes_value(true)
+                        .validator(is_number)
+                        .help("Show N levels of tasks, starting with the task itself"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("fold")
+                .about("Collapse tasks so their sub-tasks are not shown")
                 .arg(
                     Arg::with_name("id")
                         .value_name("ID")
+                        .multiple(true)
+                        .required(true)
+                        .help("Task IDs to collapse"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("unfold").about("Expand collapsed tasks").arg(
+                Arg::with_name("id")
+                    .value_name("ID")
+                    .multiple(true)
+                    .required(true)
+                    .help("Task IDs to expand"),
+            ),
+        )
+        .subcommand(
+            SubCommand::with_name("stats")
+                .about("Show summary statistics for the task list")
+                .arg(
+                    Arg::with_name("weeks")
+                        .long("weeks")
+                        .value_name("N")
+                        .takes_value(true)
+                        .default_value("4")
+                        .validator(is_number)
+                        .help("Number of weeks to report completions for"),
+                )
+                .arg(
+                    Arg::with_name("oldest")
+                        .long("oldest")
+                        .value_name("N")
                         .takes_value(true)
+                        .default_value("5")
+                        .validator(is_number)
+                        .help("Number of oldest open tasks to report"),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .takes_value(true)
+                        .possible_values(&["text", "json"])
+                        .default_value("text")
+                        .help("Output format"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about("Show the task level changes between two task files")
+                .after_help(
+                    "To review changes to a task file in git, use it as a difftool:\n\n    \
+                     git difftool -y -x 't diff' -- tasks.t",
+                )
+                .arg(
+                    Arg::with_name("old")
+                        .value_name("OLD")
                         .required(true)
-                        .help("Task ID to tag"),
+                        .help("Task file before the changes"),
                 )
                 .arg(
-                    Arg::with_name("tags")
-                        .value_name("TAG")
-                        .help("Tags")
-                        .multiple(true)
-                        .required(true),
+                    Arg::with_name("new")
+                        .value_name("NEW")
+                        .required(true)
+                        .help("Task file after the changes"),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .takes_value(true)
+                        .possible_values(&["text", "json"])
+                        .default_value("text")
+                        .help("Output format"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("merge-driver")
+                .about("Three-way merge of task files, for use as a git merge driver")
+                .after_help(
+                    "Register the driver in .git/config (or ~/.gitconfig):\n\n    \
+                     [merge \"t\"]\n        \
+                     name = t task list merge\n        \
+                     driver = t merge-driver %O %A %B\n\n\
+                     and select it for task files in .gitattributes:\n\n    \
+                     *.t merge=t\n\n\
+                     The result is written to OURS.  Tasks changed on both sides are merged field \
+                     by field and tags are merged as sets.  Tasks that still conflict are written \
+                     between conflict markers and the exit status is non-zero.  With the \
+                     sequential id scheme both branches hand out the same #N to new tasks, which \
+                     always conflict.",
+                )
+                .arg(
+                    Arg::with_name("base")
+                        .value_name("BASE")
+                        .required(true)
+                        .help("Common ancestor version (%O)"),
+                )
+                .arg(
+                    Arg::with_name("ours")
+                        .value_name("OURS")
+                        .required(true)
+                        .help("Current version, replaced by the result (%A)"),
+                )
+                .arg(
+                    Arg::with_name("theirs")
+                        .value_name("THEIRS")
+                        .required(true)
+                        .help("Other branch's version (%B)"),
+                )
+                .arg(
+                    Arg::with_name("prefer")
+                        .long("prefer")
+                        .value_name("SIDE")
+                        .takes_value(true)
+                        .possible_values(&["ours", "theirs"])
+                        .help("Resolve conflicting fields in favour of SIDE instead of markers"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("sync")
+                .about("Bring this task list and another copy of it up to date with each other")
+                .after_help(
+                    "Tasks are matched by id.  When both copies changed the same field the most \
+                     recent change wins, and tasks removed from one copy are removed from the \
+                     other unless they were changed after the removal.\n\n\
+                     Once synced, both files remember removed tasks for 90 days.  Tasks removed \
+                     before the first sync, or more than 90 days before a sync, may come back from \
+                     the other copy.",
+                )
+                .arg(
+                    Arg::with_name("other")
+                        .value_name("OTHER")
+                        .required(true)
+                        .help("FILE holding the other copy"),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("shell")
+                .about("Run commands interactively without reloading the task list"),
+        )
+        .subcommand(
+            SubCommand::with_name("serve")
+                .about("Answer JSON-RPC requests from editors and other tools")
+                .after_help(
+                    "Requests are JSON-RPC 2.0 with named params, one per line over --socket or \
+                     one per POST over HTTP, for example:\n\n    \
+                     {\"jsonrpc\": \"2.0\", \"id\": 1, \"method\": \"add\", \
+                     \"params\": {\"desc\": \"Write docs\", \"parent\": \"3f\"}}\n\n\
+                     Methods and their params, optional ones in brackets:\n\n    \
+                     list [where] [hide_completed]\n    \
+                     get id\n    \
+                     add desc [parent] [id]\n    \
+                     edit id desc\n    \
+                     complete id [force]\n    \
+                     tag id tags, e.g. [\"+urgent\", \"-later\"]\n    \
+                     move id [parent]\n    \
+                     remove id [force]\n\n\
+                     Tasks are returned as JSON with their prefix.  Every change is saved before \
+                     it is answered, and FILE is loaded again before a request if something else \
+                     changed it.  Task list errors have their exit status as error code.\n\n\
+                     HTTP requests must have Content-Type application/json and Host \
+                     127.0.0.1:PORT or localhost:PORT.  Requests with an Origin, as sent by web \
+                     browsers, are refused.",
+                )
+                .arg(
+                    Arg::with_name("socket")
+                        .long("socket")
+                        .value_name("PATH")
+                        .takes_value(true)
+                        .help("Listen on a Unix socket at PATH"),
+                )
+                .arg(
+                    Arg::with_name("port")
+                        .long("port")
+                        .value_name("PORT")
+                        .takes_value(true)
+                        .validator(is_port)
+                        .help("Listen for HTTP on localhost at PORT"),
+                )
+                .group(ArgGroup::with_name("address").args(&["socket", "port"]).required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("ui").about("Browse and edit tasks in a full-screen interface"),
+        )
         .arg(
             Arg::with_name("completions")
                 .long("completions")
@@ -138,71 +588,484 @@ fn get_args() -> clap::App<'static, 'static> {
                 .long("file")
                 .value_name("FILE")
                 .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
                 .required_unless("completions")
-                .help("FILE to use for task list"),
+                .help(
+                    "FILE to use for task list, repeat to show several files together.  A text \
+                     task file can also include others with '@include <path>' lines.  New top \
+                     level tasks are added to the first FILE.",
+                ),
+        )
+        .arg(
+            Arg::with_name("backend")
+                .long("backend")
+                .value_name("BACKEND")
+                .takes_value(true)
+                .possible_values(&["text", "sqlite"])
+                .help(
+                    "Storage backend for FILE, by default sqlite for .db/.sqlite/.sqlite3 files \
+                     and text otherwise",
+                ),
+        )
+        .arg(
+            Arg::with_name("depth")
+                .long("depth")
+                .value_name("N")
+                .takes_value(true)
+                .validator(is_number)
+                .help("Only show N levels of tasks"),
         )
         .arg(
             Arg::with_name("hide-completed")
                 .long("hide-completed")
                 .help("Don't show completed tasks"),
         )
+        .arg(
+            Arg::with_name("quiet")
+                .short("q")
+                .long("quiet")
+                .global(true)
+                .help("Only print errors and the output asked for, not what was changed"),
+        )
+        .arg(
+            Arg::with_name("ascii")
+                .long("ascii")
+                .global(true)
+                .help("Draw the task tree with ASCII characters only"),
+        )
+        .arg(
+            Arg::with_name("color")
+                .long("color")
+                .value_name("WHEN")
+                .takes_value(true)
+                .global(true)
+                .possible_values(&["auto", "always", "never"])
+                .default_value("auto")
+                .help(
+                    "Use colors always, never, or only when writing to a terminal and NO_COLOR \
+                     is not set",
+                ),
+        )
+        .arg(
+            Arg::with_name("template")
+                .long("template")
+                .value_name("TEMPLATE")
+                .takes_value(true)
+                .global(true)
+                .help(
+                    "Format of task lines, by default the 'template' setting in .t/config next \
+                     to FILE or '{indent} {checkmark} {prefix}: {tags}{desc}'.  Placeholders are \
+                     {indent} {checkmark} {prefix} {id} {tags} {desc} {due} {priority} {age} \
+                     {status} {progress}.  Tag colors are set in .t/config with lines like 'color.urgent = bright red'.",
+                ),
+        )
+}
+
+// `t __complete`, called by the completion scripts.  It is left out of `get_args` so that it is
+// not offered as a completion itself, and because clap's bash generator joins subcommand names
+// with '__' and fails on it.
+fn complete_subcommand() -> clap::App<'static, 'static> {
+    SubCommand::with_name("__complete")
+        .about("List task prefixes or tags for shell completion")
+        .setting(AppSettings::Hidden)
+        .arg(Arg::with_name("what").possible_values(&["ids", "tags"]).required(true))
+        .arg(
+            Arg::with_name("file")
+                .long("file")
+                .value_name("FILE")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
 }
 
 fn main() {
-    let matches = get_args().get_matches();
+    let matches = get_args().subcommand(complete_subcommand()).get_matches();
+    set_color(&matches);
 
     if let Some(shell) = matches.value_of("completions") {
-        match Shell::from_str(shell) {
-            Ok(Shell::Bash) => {
-                get_args().gen_completions_to("t", Shell::Bash, &mut std::io::stdout())
-            }
-            Ok(Shell::Fish) => {
-                get_args().gen_completions_to("t", Shell::Fish, &mut std::io::stdout())
-            }
-            Ok(Shell::Zsh) => {
-                get_args().gen_completions_to("t", Shell::Zsh, &mut std::io::stdout())
-            }
-            Ok(Shell::PowerShell) => {
-                get_args().gen_completions_to("t", Shell::PowerShell, &mut std::io::stdout())
-            }
-            Ok(Shell::Elvish) => {
-                get_args().gen_completions_to("t", Shell::Elvish, &mut std::io::stdout())
-            }
-            _ => println!("unknown shell: {}", shell),
-        }
+        print_completions(shell);
         std::process::exit(0);
     }
 
-    let task_file = matches.value_of("file").unwrap();
+    // Commands that don't use the task file
+    if let ("merge-driver", Some(merge_matches)) = matches.subcommand() {
+        merge_driver(merge_matches);
+    }
+    if let ("__complete", Some(complete_matches)) = matches.subcommand() {
+        complete_candidates(&matches, complete_matches);
+    }
+    if let ("diff", Some(diff_matches)) = matches.subcommand() {
+        if let Err(e) = show_diff(diff_matches) {
+            exit_with_error(e);
+        }
+        return;
+    }
+
+    let task_files: Vec<&str> = match matches.values_of("file") {
+        Some(task_files) => task_files.collect(),
+        None => clap::Error::with_description(
+            "The following required arguments were not provided:\n    --file <FILE>",
+            clap::ErrorKind::MissingRequiredArgument,
+        )
+        .exit(),
+    };
 
-    match matches.subcommand() {
-        ("add", Some(add_matches)) => add_task(task_file, add_matches),
-        ("edit", Some(edit_matches)) => edit_task(task_file, edit_matches),
-        ("remove", Some(remove_matches)) => remove_task(task_file, remove_matches),
-        ("complete", Some(complete_matches)) => complete_task(task_file, complete_matches),
-        ("uncomplete", Some(uncomplete_matches)) => uncomplete_task(task_file, uncomplete_matches),
-        ("tag", Some(tag_matches)) => tag_task(task_file, tag_matches),
-        ("", None) => show_tasks(task_file, &matches),
+    // Load Task List
+    let mut tasks = match load(&task_files, &matches) {
+        Ok(tasks) => tasks,
+        Err(e) => exit_with_error(e),
+    };
+
+    if let ("shell", Some(_)) = matches.subcommand() {
+        if let Err(e) = shell::run(&mut tasks) {
+            exit_with_error(e);
+        }
+        return;
+    }
+
+    if let ("serve", Some(serve_matches)) = matches.subcommand() {
+        let address = match serve_matches.value_of("socket") {
+            Some(path) => serve::Address::Socket(path.to_string()),
+            None => serve::Address::Port(value_t_or_exit!(serve_matches, "port", u16)),
+        };
+        // The task list is loaded again whenever it is changed by something else
+        let task_files: Vec<String> = task_files.iter().map(|file| file.to_string()).collect();
+        let matches = matches.clone();
+        let reload = move || {
+            let task_files: Vec<&str> = task_files.iter().map(String::as_str).collect();
+            load(&task_files, &matches)
+        };
+        if let Err(e) = serve::serve(tasks, &address, reload) {
+            exit_with_error(e);
+        }
+        return;
+    }
+
+    if let Err(e) = run_command(&mut tasks, &matches) {
+        exit_with_error(e);
+    }
+}
+
+// Turn the `colored` styling on or off according to --color.
+fn set_color(matches: &ArgMatches) {
+    let color = match matches.value_of("color") {
+        Some("always") => true,
+        Some("never") => false,
+        _ => {
+            std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+                && io::stdout().is_terminal()
+        }
+    };
+    colored::control::set_override(color);
+}
+
+// Load the task list and set it up as asked for on the command line and in .t/config.
+fn load(task_files: &[&str], matches: &ArgMatches) -> Result<TaskList, TaskListError> {
+    let mut tasks = task_list::create_from_files(task_files, matches.value_of("backend"))?;
+    tasks.set_quiet(matches.is_present("quiet"));
+    tasks.set_ascii(matches.is_present("ascii"));
+    tasks.set_width(terminal_width());
+    configure(&mut tasks, matches)?;
+    Ok(tasks)
+}
+
+// Apply the settings in the task file's config and --template.
+fn configure(tasks: &mut TaskList, matches: &ArgMatches) -> Result<(), TaskListError> {
+    let config = config::load(tasks.file())?;
+
+    let template = matches.value_of("template").map(String::from).or(config.template);
+    if let Some(template) = template {
+        tasks.set_template(template::Template::parse(&template)?);
+    }
+    tasks.set_tag_colors(config.tag_colors);
+    // Stable prefixes given to tasks for the first time are saved along with the next change, until
+    // then the same ones are given again each time the list is loaded
+    if config.stable_prefixes {
+        tasks.set_stable_prefixes();
+    }
+    let hooks = config::dir(tasks.file()).join("hooks");
+    if hooks.is_dir() {
+        tasks.set_hooks(hooks);
+    }
+
+    Ok(())
+}
+
+// Width of the terminal that output is written to, None when it is not written to a terminal.
+fn terminal_width() -> Option<usize> {
+    if !io::stdout().is_terminal() {
+        return None;
+    }
+    crossterm::terminal::size()
+        .ok()
+        .map(|(columns, _)| columns as usize)
+        .filter(|&columns| columns > 0)
+}
+
+fn exit_with_error(error: TaskListError) -> ! {
+    eprintln!("Error: {}", error);
+    std::process::exit(error.exit_code());
+}
+
+fn print_completions(shell: &str) {
+    let shell = match Shell::from_str(shell) {
+        Ok(shell) => shell,
+        Err(_) => {
+            println!("unknown shell: {}", shell);
+            return;
+        }
+    };
+
+    let mut script = Vec::new();
+    match shell {
+        // clap's zsh generator loops forever when a subcommand alias is the name of the program,
+        // as 't' for tag is, so generate the script under another name
+        Shell::Zsh => {
+            get_args().bin_name("tcompletions").gen_completions_to(
+                "tcompletions",
+                shell,
+                &mut script,
+            );
+        }
+        _ => get_args().gen_completions_to("t", shell, &mut script),
+    }
+    let script = String::from_utf8_lossy(&script).replace("tcompletions", "t");
+
+    print!("{}", complete::add_dynamic_completions(&get_args(), shell, &script));
+}
+
+// Print the task prefixes or tags to complete on, for the completion scripts.  Completion has to
+// stay quiet, so nothing is printed if the task list can't be loaded.
+fn complete_candidates(matches: &ArgMatches, complete_matches: &ArgMatches) -> ! {
+    let files: Vec<&str> = match complete_matches.values_of("file") {
+        Some(files) => files.collect(),
+        None => matches.values_of("file").into_iter().flatten().collect(),
+    };
+
+    if !files.is_empty() {
+        if let Ok(mut tasks) = task_list::create_from_files(&files, matches.value_of("backend")) {
+            let _ = configure(&mut tasks, matches);
+            complete::print_candidates(&tasks, complete_matches.value_of("what").unwrap());
+        }
+    }
+
+    std::process::exit(0);
+}
+
+fn merge_driver(matches: &ArgMatches) -> ! {
+    let prefer = match matches.value_of("prefer") {
+        Some("ours") => Some(merge::Prefer::Ours),
+        Some("theirs") => Some(merge::Prefer::Theirs),
+        _ => None,
+    };
+
+    match merge::merge_files(
+        matches.value_of("base").unwrap(),
+        matches.value_of("ours").unwrap(),
+        matches.value_of("theirs").unwrap(),
+        prefer,
+    ) {
+        Ok(0) => std::process::exit(0),
+        Ok(conflicts) => {
+            eprintln!("{} conflicting task(s)", conflicts);
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(2);
+        }
+    }
+}
+
+// Run a single command against a loaded task list and save it if the command changed anything.
+// On error the task list is left unchanged and not saved.
+fn run_command(tasks: &mut TaskList, matches: &ArgMatches) -> Result<(), TaskListError> {
+    let snapshot = tasks.clone();
+
+    let result = match matches.subcommand() {
+        ("add", Some(add_matches)) => add_task(tasks, add_matches),
+        ("copy", Some(copy_matches)) => copy_task(tasks, copy_matches),
+        ("template", Some(template_matches)) => template_task(tasks, template_matches),
+        ("edit", Some(edit_matches)) => edit_task(tasks, edit_matches),
+        ("remove", Some(remove_matches)) => remove_task(tasks, remove_matches),
+        ("complete", Some(complete_matches)) => complete_task(tasks, complete_matches),
+        ("uncomplete", Some(uncomplete_matches)) => uncomplete_task(tasks, uncomplete_matches),
+        ("rename-id", Some(rename_matches)) => rename_task(tasks, rename_matches),
+        ("tag", Some(tag_matches)) => tag_task(tasks, tag_matches),
+        ("fold", Some(fold_matches)) => fold_task(tasks, fold_matches, true),
+        ("unfold", Some(unfold_matches)) => fold_task(tasks, unfold_matches, false),
+        ("id-scheme", Some(scheme_matches)) => id_scheme(tasks, scheme_matches),
+        ("list", Some(list_matches)) => return list_tasks(tasks, matches, list_matches),
+        ("show", Some(show_matches)) => return show_task(tasks, matches, show_matches),
+        ("stats", Some(stats_matches)) => return show_stats(tasks, stats_matches),
+        ("sync", Some(sync_matches)) => return sync_tasks(tasks, sync_matches),
+        ("ui", Some(_)) => return run_ui(tasks, matches),
+        // main runs these itself, only `t shell` passes them on
+        (name, Some(_)) if shell::NOT_IN_SHELL.contains(&name) => {
+            return Err(TaskListError::NestedShell(name.to_string()))
+        }
+        ("diff", Some(diff_matches)) => return show_diff(diff_matches),
+        ("", None) => return show_tasks(tasks, matches),
         _ => unreachable!(),
+    };
+
+    match result {
+        // Save Task List, or undo the changes that couldn't be saved
+        Ok(()) => tasks.save().inspect_err(|_| *tasks = snapshot),
+        Err(e) => {
+            *tasks = snapshot;
+            Err(e)
+        }
     }
 }
 
-fn show_tasks(task_file: &str, matches: &ArgMatches) {
-    // Load Task List
-    let tasks = task_list::create_from_file(task_file);
+// The value of --depth, if given.
+fn depth(matches: &ArgMatches) -> Option<usize> {
+    match matches.is_present("depth") {
+        true => Some(value_t_or_exit!(matches, "depth", usize)),
+        false => None,
+    }
+}
+
+fn show_tasks(tasks: &TaskList, matches: &ArgMatches) -> Result<(), TaskListError> {
     let hide_completed = matches.is_present("hide-completed");
 
     // Show Task List
-    tasks.show(hide_completed);
+    tasks.show(hide_completed, depth(matches));
+
+    Ok(())
 }
 
-fn add_task(task_file: &str, matches: &ArgMatches) {
-    // Handle Command Line Options
-    let parent_id = matches.value_of("parent_id");
+fn id_scheme(tasks: &mut TaskList, matches: &ArgMatches) -> Result<(), TaskListError> {
+    // Change the id scheme
+    if let Some(scheme) = matches.value_of("scheme") {
+        tasks.set_sequential_ids(scheme == "sequential");
+    }
+
+    // Show the id scheme
+    if !tasks.quiet() || !matches.is_present("scheme") {
+        match tasks.next_id() {
+            Some(next_id) => println!("sequential, the next task is #{}", next_id),
+            None => println!("hash"),
+        }
+    }
+
+    Ok(())
+}
+
+fn list_tasks(
+    tasks: &TaskList,
+    matches: &ArgMatches,
+    list_matches: &ArgMatches,
+) -> Result<(), TaskListError> {
+    // Handle command line options
+    let hide_completed =
+        list_matches.is_present("hide-completed") || matches.is_present("hide-completed");
+    let ids = match list_matches.value_of("where") {
+        Some(expr) => Some(filter::Filter::parse(expr)?.select(tasks)?),
+        None => None,
+    };
+
+    // List Tasks
+    if !list_matches.is_present("flat") {
+        match ids {
+            Some(ids) => tasks.show_matching(&ids, hide_completed, depth(matches)),
+            None => tasks.show(hide_completed, depth(matches)),
+        }
+        return Ok(());
+    }
+
+    let sort = match list_matches.value_of("sort") {
+        Some("due") => task_list::Sort::Due,
+        Some("priority") => task_list::Sort::Priority,
+        Some("completed") => task_list::Sort::Completed,
+        _ => task_list::Sort::Created,
+    };
+    let group = match list_matches.value_of("group-by") {
+        Some("tag") => Some(task_list::Group::Tag),
+        Some("status") => Some(task_list::Group::Status),
+        Some("parent") => Some(task_list::Group::Parent),
+        _ => None,
+    };
+    let ids = ids.unwrap_or_else(|| tasks.tasks().iter().map(|t| t.id().to_string()).collect());
+    tasks.show_flat(&ids, hide_completed, sort, group);
+
+    Ok(())
+}
+
+fn show_task(
+    tasks: &TaskList,
+    matches: &ArgMatches,
+    show_matches: &ArgMatches,
+) -> Result<(), TaskListError> {
+    // Handle command line options
+    let hide_completed = matches.is_present("hide-completed");
+    let depth = depth(show_matches).or_else(|| depth(matches));
+
+    // Show Task, or all tasks
+    match show_matches.value_of("id") {
+        Some(id) => tasks.show_task(id, hide_completed, depth),
+        None => {
+            tasks.show(hide_completed, depth);
+            Ok(())
+        }
+    }
+}
+
+fn run_ui(tasks: &mut TaskList, matches: &ArgMatches) -> Result<(), TaskListError> {
+    let hide_completed = matches.is_present("hide-completed");
+
+    // Run UI, changes are saved as they are made
+    let quiet = tasks.quiet();
+    tasks.set_quiet(true);
+    let result = ui::run(tasks, hide_completed);
+    tasks.set_quiet(quiet);
+
+    Ok(result?)
+}
+
+fn show_stats(tasks: &TaskList, matches: &ArgMatches) -> Result<(), TaskListError> {
+    // Handle command line options
+    let weeks = value_t_or_exit!(matches, "weeks", usize);
+    let oldest = value_t_or_exit!(matches, "oldest", usize);
+
+    // Show Statistics
+    let stats = stats::compute(tasks, weeks, oldest);
+    match matches.value_of("format") {
+        Some("json") => println!("{}", stats.to_json()),
+        _ => stats.show(tasks.ascii()),
+    }
+
+    Ok(())
+}
+
+fn show_diff(matches: &ArgMatches) -> Result<(), TaskListError> {
+    let old = task_list::create_from_file(matches.value_of("old").unwrap(), None)?;
+    let new = task_list::create_from_file(matches.value_of("new").unwrap(), None)?;
 
-    // Concatenate all words into a single description string
+    // Show Differences
+    let diff = diff::compute(&old, &new);
+    match matches.value_of("format") {
+        Some("json") => println!("{}", diff.to_json()),
+        _ => diff.show(&old, &new),
+    }
+
+    Ok(())
+}
+
+fn sync_tasks(tasks: &mut TaskList, matches: &ArgMatches) -> Result<(), TaskListError> {
+    // Load the other copy, both lists are saved by the sync
+    let mut other = task_list::create_from_file(matches.value_of("other").unwrap(), None)?;
+
+    sync::sync(tasks, &mut other)
+}
+
+// Concatenate all words into a single description string
+fn join_words(matches: &ArgMatches, name: &str) -> String {
     let mut desc = String::from("");
-    match matches.values_of("task") {
+    match matches.values_of(name) {
         None => {}
         Some(words) => {
             for word in words {
@@ -213,55 +1076,184 @@ fn add_task(task_file: &str, matches: &ArgMatches) {
             }
         }
     }
+    desc
+}
 
-    // Load Task List
-    let mut tasks = task_list::create_from_file(task_file);
+fn add_task(tasks: &mut TaskList, matches: &ArgMatches) -> Result<(), TaskListError> {
+    // Handle Command Line Options
+    let parent_id = matches.value_of("parent_id");
+
+    // Add Tasks from a file or stdin
+    if let Some(from) = matches.value_of("from") {
+        return if from == "-" {
+            add_tasks_from(tasks, parent_id, io::stdin().lock())
+        } else {
+            add_tasks_from(tasks, parent_id, BufReader::new(File::open(from)?))
+        };
+    }
+
+    let desc = join_words(matches, "task");
+    let due = matches.value_of("due").map(parse_due).transpose()?.flatten();
 
     // Add Task
-    if let Err(e) = tasks.add_task(parent_id, matches.value_of("id"), &desc) {
-        eprintln!("Error: {}", e);
-        return;
+    let id = tasks.add_task(parent_id, matches.value_of("id"), &desc)?;
+    let task = tasks.get_task(&id)?;
+    task.set_due(due.as_deref());
+    task.set_priority(matches.value_of("priority").and_then(|priority| priority.parse().ok()));
+
+    Ok(())
+}
+
+// A --due value, None for 'none'.
+fn parse_due(due: &str) -> Result<Option<String>, TaskListError> {
+    match due {
+        "none" => Ok(None),
+        _ => task::parse_date(due).map(Some).ok_or_else(|| TaskListError::BadDate(due.to_string())),
     }
+}
+
+// Add a task for every line read from `reader`.  A line indented deeper than the line before it
+// becomes a sub-task of that line, and words like #tag are removed from the description and added
+// as tags.
+fn add_tasks_from<R: BufRead>(
+    tasks: &mut TaskList,
+    parent_id: Option<&str>,
+    reader: R,
+) -> Result<(), TaskListError> {
+    // Indentation and id of the tasks that later lines may be nested under
+    let mut parents: Vec<(usize, String)> = Vec::new();
+
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let indent: usize = line
+            .chars()
+            .take_while(|c| c.is_whitespace())
+            .map(|c| if c == '\t' { 4 } else { 1 })
+            .sum();
 
-    // Save Task List
-    tasks.save();
+        // Split out tags, and drop list markers pasted along with the text
+        let mut desc = String::from("");
+        let mut tags = Vec::new();
+        let line = line.trim_start();
+        let line = line.strip_prefix("- ").or_else(|| line.strip_prefix("* ")).unwrap_or(line);
+        for word in line.split_whitespace() {
+            match word.strip_prefix('#') {
+                Some(tag) if !tag.is_empty() => tags.push(tag),
+                _ => {
+                    if !desc.is_empty() {
+                        desc += " ";
+                    }
+                    desc += word;
+                }
+            }
+        }
+
+        while parents.last().is_some_and(|(parent_indent, _)| *parent_indent >= indent) {
+            parents.pop();
+        }
+        let parent = parents.last().map(|(_, id)| id.as_str()).or(parent_id);
+
+        let id = tasks.add_task(parent, None, &desc)?;
+        let task = tasks.get_task(&id)?;
+        for tag in tags {
+            task.add_tag(tag);
+        }
+
+        parents.push((indent, id));
+    }
+
+    Ok(())
 }
 
-fn edit_task(task_file: &str, matches: &ArgMatches) {
+fn copy_task(tasks: &mut TaskList, matches: &ArgMatches) -> Result<(), TaskListError> {
     // Handle command line options
-    // Get ID
     let id = matches.value_of("id").unwrap();
+    let subtree = tasks.subtree(id)?;
 
-    // Concatenate all words into a single description string
-    let mut desc = String::from("");
-    match matches.values_of("task") {
-        None => {}
-        Some(words) => {
-            for word in words {
-                if !desc.is_empty() {
-                    desc += " ";
+    // Copy Tasks, next to the original unless given a parent
+    let parent_id = match matches.value_of("parent_id") {
+        Some(parent_id) => Some(parent_id.to_string()),
+        None => subtree[0].parent_id().clone(),
+    };
+    let copies = tasks.add_copies(&subtree, parent_id.as_deref(), matches.is_present("reset"))?;
+
+    if !tasks.quiet() {
+        println!(
+            "copied task {} to {} ({} task(s))",
+            id,
+            display_prefix(tasks, &copies[0]),
+            copies.len()
+        );
+    }
+
+    Ok(())
+}
+
+fn template_task(tasks: &mut TaskList, matches: &ArgMatches) -> Result<(), TaskListError> {
+    match matches.subcommand() {
+        ("save", Some(save_matches)) => {
+            let name = save_matches.value_of("name").unwrap();
+            let count = task_templates::save(tasks, save_matches.value_of("id").unwrap(), name)?;
+            if !tasks.quiet() {
+                println!("saved template {} ({} task(s))", name, count);
+            }
+        }
+        ("use", Some(use_matches)) => {
+            let name = use_matches.value_of("name").unwrap();
+            let template = task_templates::load(tasks, name)?;
+            let parent_id = use_matches.value_of("parent_id");
+            let copies = tasks.add_copies(&template, parent_id, use_matches.is_present("reset"))?;
+            if !tasks.quiet() {
+                if let Some(id) = copies.first() {
+                    println!(
+                        "added task {} from template {} ({} task(s))",
+                        display_prefix(tasks, id),
+                        name,
+                        copies.len()
+                    );
                 }
-                desc += word;
             }
         }
+        ("list", Some(_)) => {
+            for name in task_templates::names(tasks)? {
+                println!("{}", name);
+            }
+        }
+        _ => unreachable!(),
     }
 
-    // Load Task List
-    let mut tasks = task_list::create_from_file(task_file);
+    Ok(())
+}
+
+fn edit_task(tasks: &mut TaskList, matches: &ArgMatches) -> Result<(), TaskListError> {
+    // Handle command line options
+    // Get ID
+    let id = matches.value_of("id").unwrap();
+    let mut desc = join_words(matches, "task");
+    let due = matches.value_of("due").map(parse_due).transpose()?;
+    let priority = matches.value_of("priority").map(|priority| priority.parse().ok());
 
     // Get Task
-    let task = match tasks.get_task(id) {
-        Err(e) => {
-            eprintln!("Error: {}", e);
-            return;
-        }
-        Ok(task) => task,
-    };
+    let task = tasks.get_task(id)?;
+
+    if let Some(due) = due {
+        task.set_due(due.as_deref());
+    }
+    if let Some(priority) = priority {
+        task.set_priority(priority);
+    }
 
     // Update description; if none provided on command line open editor with current value as
-    // default.
+    // default, unless only the due date or priority are changed.
     if desc.is_empty() {
-        if let Ok(edited) = edit::edit(&task.desc()) {
+        if matches.is_present("due") || matches.is_present("priority") {
+            return Ok(());
+        }
+        if let Ok(edited) = edit::edit(task.desc()) {
             desc = edited;
         }
     }
@@ -270,106 +1262,224 @@ fn edit_task(task_file: &str, matches: &ArgMatches) {
 
     task.set_desc(&desc);
 
-    // Save Task List
-    tasks.save();
+    Ok(())
 }
 
-fn remove_task(task_file: &str, matches: &ArgMatches) {
-    // Handle command line options
-    let id = matches.value_of("id").unwrap();
-    let force = matches.is_present("force");
+fn rename_task(tasks: &mut TaskList, matches: &ArgMatches) -> Result<(), TaskListError> {
+    // Rename Task
+    tasks.rename_task(matches.value_of("id").unwrap(), matches.value_of("new_id").unwrap())
+}
 
-    // Load Task List
-    let mut tasks = task_list::create_from_file(task_file);
+// Full ids of the tasks selected by the ID arguments and the --where filter.  All ids are resolved
+// before anything is changed, so one bad prefix fails the whole batch.
+fn selected_ids<'a, I>(
+    tasks: &TaskList,
+    ids: I,
+    matches: &ArgMatches,
+) -> Result<Vec<String>, TaskListError>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let mut full_ids = Vec::new();
+    for id in ids {
+        full_ids.push(tasks.get_full_id(id)?);
+    }
 
-    // Remove Task
-    if let Err(e) = tasks.remove_task(id, force) {
-        eprintln!("Error: {}", e);
-        return;
+    if let Some(expr) = matches.value_of("where") {
+        full_ids.extend(filter::Filter::parse(expr)?.select(tasks)?);
     }
 
-    // Save Task List
-    tasks.save();
+    // Drop duplicates, keeping the first occurrence
+    let mut seen = HashSet::new();
+    full_ids.retain(|id| seen.insert(id.to_string()));
+
+    Ok(full_ids)
+}
+
+// Prefix to show the user for a full task id.
+fn display_prefix(tasks: &TaskList, full_id: &str) -> String {
+    tasks.prefix(full_id).map_or(full_id, |p| p.as_str()).to_string()
 }
 
-fn complete_task(task_file: &str, matches: &ArgMatches) {
+fn remove_task(tasks: &mut TaskList, matches: &ArgMatches) -> Result<(), TaskListError> {
     // Handle command line options
-    let id = matches.value_of("id").unwrap();
+    let ids = matches.values_of("id").into_iter().flatten();
     let force = matches.is_present("force");
 
-    // Load Task List
-    let mut tasks = task_list::create_from_file(task_file);
+    // Check every task before removing any, sub-tasks removed in the same batch don't count
+    let full_ids = selected_ids(tasks, ids, matches)?;
+    let selected: HashSet<String> = full_ids.iter().cloned().collect();
+    if !force && full_ids.iter().any(|id| has_unselected(tasks, id, &selected, |_| true)) {
+        return Err(TaskListError::RemoveHasChildren);
+    }
 
-    // Complete Task
-    if let Err(e) = tasks.complete_task(id, force) {
-        eprintln!("Error: {}", e);
-        return;
+    // Remove Tasks
+    for full_id in deepest_first(tasks, full_ids) {
+        // Already removed along with its parent
+        if tasks.get_task_ref(&full_id).is_err() {
+            continue;
+        }
+
+        let prefix = display_prefix(tasks, &full_id);
+        tasks.remove_task(&prefix, force)?;
     }
 
-    // Save Task List
-    tasks.save();
+    Ok(())
 }
 
-fn uncomplete_task(task_file: &str, matches: &ArgMatches) {
+fn complete_task(tasks: &mut TaskList, matches: &ArgMatches) -> Result<(), TaskListError> {
     // Handle command line options
-    let id = matches.value_of("id").unwrap();
+    let ids = matches.values_of("id").into_iter().flatten();
+    let force = matches.is_present("force");
 
-    // Load Task List
-    let mut tasks = task_list::create_from_file(task_file);
+    // Check every task before completing any, sub-tasks completed in the same batch don't count
+    let full_ids = selected_ids(tasks, ids, matches)?;
+    let open = |task: &task::Task| !task.is_completed();
+    let selected: HashSet<String> = full_ids.iter().cloned().collect();
+    if !force && full_ids.iter().any(|id| has_unselected(tasks, id, &selected, open)) {
+        return Err(TaskListError::CompleteHasChildren);
+    }
 
-    // Get Task
-    let task = match tasks.get_task(id) {
-        Err(e) => {
-            eprintln!("Error: {}", e);
-            return;
+    // Complete Tasks
+    for full_id in deepest_first(tasks, full_ids) {
+        let prefix = display_prefix(tasks, &full_id);
+        tasks.complete_task(&prefix, force)?;
+    }
+
+    Ok(())
+}
+
+// Whether task `full_id` has a sub-task matching `check` that is not in `selected`.
+fn has_unselected(
+    tasks: &TaskList,
+    full_id: &str,
+    selected: &HashSet<String>,
+    check: impl Fn(&task::Task) -> bool,
+) -> bool {
+    tasks
+        .descendant_ids(full_id)
+        .iter()
+        .filter(|id| !selected.contains(*id))
+        .any(|id| tasks.task_by_id(id).is_some_and(&check))
+}
+
+// `full_ids` with sub-tasks before their parents, otherwise in the same order.
+fn deepest_first(tasks: &TaskList, mut full_ids: Vec<String>) -> Vec<String> {
+    let depth = |id: &String| {
+        let mut depth = 0;
+        let mut seen = HashSet::from([id.to_string()]);
+        let mut parent_id = tasks.task_by_id(id).and_then(|task| task.parent_id().clone());
+        while let Some(id) = parent_id {
+            // Parents can form a cycle in a hand edited file
+            if !seen.insert(id.to_string()) {
+                break;
+            }
+            depth += 1;
+            parent_id = tasks.task_by_id(&id).and_then(|task| task.parent_id().clone());
         }
-        Ok(task) => task,
+        depth
     };
+    full_ids.sort_by_cached_key(|id| std::cmp::Reverse(depth(id)));
+    full_ids
+}
 
-    // Unmplete Task
-    task.set_complete(false);
+fn uncomplete_task(tasks: &mut TaskList, matches: &ArgMatches) -> Result<(), TaskListError> {
+    // Handle command line options
+    let ids = matches.values_of("id").into_iter().flatten();
 
-    // Save Task List
-    tasks.save();
+    // Uncomplete Tasks
+    for full_id in selected_ids(tasks, ids, matches)? {
+        tasks.get_task(&full_id)?.set_complete(false);
+    }
+
+    Ok(())
 }
 
-fn tag_task(task_file: &str, matches: &ArgMatches) {
+fn fold_task(
+    tasks: &mut TaskList,
+    matches: &ArgMatches,
+    collapsed: bool,
+) -> Result<(), TaskListError> {
     // Handle command line options
-    // Get ID
-    let id = matches.value_of("id").unwrap();
+    let ids = matches.values_of("id").into_iter().flatten();
 
-    // Load Task List
-    let mut tasks = task_list::create_from_file(task_file);
+    // Collapse or expand Tasks
+    for full_id in selected_ids(tasks, ids, matches)? {
+        tasks.get_task(&full_id)?.set_collapsed(collapsed);
+    }
 
-    // Get Task
-    let task = match tasks.get_task(id) {
-        Err(e) => {
-            eprintln!("Error: {}", e);
-            return;
-        }
-        Ok(task) => task,
+    Ok(())
+}
+
+fn tag_task(tasks: &mut TaskList, matches: &ArgMatches) -> Result<(), TaskListError> {
+    // Handle command line options
+    // The first argument is the ID unless tasks are selected with --ids or --where
+    let args: Vec<&str> = matches.values_of("args").into_iter().flatten().collect();
+    let (ids, tags) = if matches.is_present("ids") || matches.is_present("where") {
+        let ids = matches.value_of("ids").map_or(Vec::new(), |ids| {
+            ids.split(',').map(str::trim).filter(|id| !id.is_empty()).collect()
+        });
+        (ids, &args[..])
+    } else {
+        (vec![args[0]], &args[1..])
     };
+    if tags.is_empty() {
+        return Err(TaskListError::MissingTags);
+    }
 
     // Update tags
-    match matches.values_of("tags") {
-        None => {}
-        Some(tags) => {
-            for tag in tags {
-                if tag.starts_with('-') {
-                    // Remove '-' from tag name.
-                    let mut chars = tag.chars();
-                    chars.next();
-                    let tag = chars.as_str();
+    let quiet = tasks.quiet();
+    for full_id in selected_ids(tasks, ids, matches)? {
+        let task = tasks.get_task(&full_id)?;
+        for tag in tags {
+            if let Some(tag) = tag.strip_prefix('-') {
+                if !quiet {
                     println!("removing tag: {}", tag);
-                    task.remove_tag(tag);
-                } else {
+                }
+                task.remove_tag(tag);
+            } else {
+                let tag = tag.trim_start_matches('+');
+                if !quiet {
                     println!("adding tag: {}", tag);
-                    task.add_tag(tag);
                 }
+                task.add_tag(tag);
             }
         }
     }
 
-    // Save Task List
-    tasks.save();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::temp_file;
+    use std::fs;
+
+    #[test]
+    fn add_tasks_from_nests_by_indentation() {
+        let file = temp_file("from.t");
+        let mut tasks = task_list::create_from_file(&file, Some("text")).unwrap();
+        tasks.set_quiet(true);
+        tasks.add_task(None, Some("inbox"), "Inbox").unwrap();
+
+        let outline =
+            "Release #work\n  - Tag it\n\tgrep foo | wc\n      Deeper #later #now\n\n* Top\n";
+        add_tasks_from(&mut tasks, Some("inbox"), outline.as_bytes()).unwrap();
+        tasks.save().unwrap();
+
+        let tasks = task_list::create_from_file(&file, Some("text")).unwrap();
+        let task = |desc: &str| tasks.tasks().iter().find(|task| task.desc() == desc).unwrap();
+        let parent = |desc: &str| task(desc).parent_id().clone();
+        assert_eq!(tasks.tasks().len(), 6);
+        assert_eq!(parent("Release").as_deref(), Some("inbox"));
+        assert_eq!(task("Release").tags(), &["work"]);
+        assert_eq!(parent("Tag it"), Some(task("Release").id().to_string()));
+        // A tab counts as four spaces
+        assert_eq!(parent("grep foo | wc"), Some(task("Tag it").id().to_string()));
+        assert_eq!(parent("Deeper"), Some(task("grep foo | wc").id().to_string()));
+        assert_eq!(task("Deeper").tags(), &["later", "now"]);
+        assert_eq!(parent("Top").as_deref(), Some("inbox"));
+        fs::remove_file(file).unwrap();
+    }
 }
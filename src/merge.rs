@@ -0,0 +1,373 @@
+use super::task;
+use super::task_list::{self, TaskListError};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+// Which side wins when both sides changed the same field differently.  Without a preference the
+// task is written with conflict markers.
+#[derive(Clone, Copy)]
+pub enum Prefer {
+    Ours,
+    Theirs,
+}
+
+enum Merged {
+    Task(task::Task),
+    // Conflicting versions, either side may have removed the task
+    Conflict(Option<Box<task::Task>>, Option<Box<task::Task>>),
+}
+
+// Three way merge of a single field, None if both sides changed it differently.
+fn merge_field<T: PartialEq + Clone>(base: Option<&T>, ours: &T, theirs: &T) -> Option<T> {
+    if ours == theirs || base == Some(theirs) {
+        Some(ours.clone())
+    } else if base == Some(ours) {
+        Some(theirs.clone())
+    } else {
+        None
+    }
+}
+
+// Fall back to the preferred side for a conflicting field.
+fn resolve<T: Clone>(merged: Option<T>, ours: &T, theirs: &T, prefer: Option<Prefer>) -> Option<T> {
+    match (merged, prefer) {
+        (Some(value), _) => Some(value),
+        (None, Some(Prefer::Ours)) => Some(ours.clone()),
+        (None, Some(Prefer::Theirs)) => Some(theirs.clone()),
+        (None, None) => None,
+    }
+}
+
+// Tags merge as a set: a tag added on either side is kept, a tag removed on either side is
+// removed.
+fn merge_tags(base: Option<&Vec<String>>, ours: &[String], theirs: &[String]) -> Vec<String> {
+    let in_base = |tag: &String| base.is_some_and(|base| base.contains(tag));
+
+    let mut tags = Vec::new();
+    for tag in ours.iter().chain(theirs.iter()) {
+        let keep = if in_base(tag) { ours.contains(tag) && theirs.contains(tag) } else { true };
+        if keep && !tags.contains(tag) {
+            tags.push(tag.to_string());
+        }
+    }
+    tags
+}
+
+fn merge_task(
+    base: Option<&task::Task>,
+    ours: &task::Task,
+    theirs: &task::Task,
+    prefer: Option<Prefer>,
+) -> Merged {
+    let desc = resolve(
+        merge_field(base.map(|b| b.desc()), ours.desc(), theirs.desc()),
+        ours.desc(),
+        theirs.desc(),
+        prefer,
+    );
+    let parent_id = resolve(
+        merge_field(base.map(|b| b.parent_id()), ours.parent_id(), theirs.parent_id()),
+        ours.parent_id(),
+        theirs.parent_id(),
+        prefer,
+    );
+
+    let due = resolve(
+        merge_field(base.map(|b| b.due()).as_ref(), &ours.due(), &theirs.due()),
+        &ours.due(),
+        &theirs.due(),
+        prefer,
+    );
+    let priority = resolve(
+        merge_field(base.map(|b| b.priority()).as_ref(), &ours.priority(), &theirs.priority()),
+        &ours.priority(),
+        &theirs.priority(),
+        prefer,
+    );
+
+    // When both sides completed the task the later completion wins
+    let completed_timestamp = match merge_field(
+        base.map(|b| b.completed_timestamp()).as_ref(),
+        &ours.completed_timestamp(),
+        &theirs.completed_timestamp(),
+    ) {
+        None if ours.is_completed() && theirs.is_completed() => {
+            Some(ours.completed_timestamp().max(theirs.completed_timestamp()))
+        }
+        merged => {
+            resolve(merged, &ours.completed_timestamp(), &theirs.completed_timestamp(), prefer)
+        }
+    };
+
+    match (desc, parent_id, due, priority, completed_timestamp) {
+        (Some(desc), Some(parent_id), Some(due), Some(priority), Some(completed_timestamp)) => {
+            let mut task = ours.clone();
+            task.set_desc(&desc);
+            task.set_parent_id(parent_id.as_deref());
+            task.set_due(due);
+            task.set_priority(priority);
+            task.set_completed_timestamp(completed_timestamp);
+            task.set_tags(merge_tags(base.map(|b| b.tags()), ours.tags(), theirs.tags()));
+            task.set_modified(ours.modified().max(theirs.modified()));
+            Merged::Task(task)
+        }
+        _ => Merged::Conflict(Some(Box::new(ours.clone())), Some(Box::new(theirs.clone()))),
+    }
+}
+
+fn merge(
+    base: &[task::Task],
+    ours: &[task::Task],
+    theirs: &[task::Task],
+    prefer: Option<Prefer>,
+) -> Vec<Merged> {
+    let by_id = |tasks: &'_ [task::Task]| -> HashMap<String, task::Task> {
+        tasks.iter().map(|task| (task.id().to_string(), task.clone())).collect()
+    };
+    let base = by_id(base);
+    let ours = by_id(ours);
+    let theirs = by_id(theirs);
+
+    let ids: BTreeSet<&String> = ours.keys().chain(theirs.keys()).collect();
+
+    let mut merged = Vec::new();
+    for id in ids {
+        let base = base.get(id);
+        match (ours.get(id), theirs.get(id)) {
+            (Some(ours), Some(theirs)) => merged.push(merge_task(base, ours, theirs, prefer)),
+            // Added on one side, or removed on one side without changes on the other
+            (Some(task), None) | (None, Some(task)) if base.is_none() => {
+                merged.push(Merged::Task(task.clone()))
+            }
+            (Some(task), None) | (None, Some(task)) if base == Some(task) => {}
+            // Removed on one side but changed on the other
+            (Some(ours), None) => match prefer {
+                Some(Prefer::Ours) => merged.push(Merged::Task(ours.clone())),
+                Some(Prefer::Theirs) => {}
+                None => merged.push(Merged::Conflict(Some(Box::new(ours.clone())), None)),
+            },
+            (None, Some(theirs)) => match prefer {
+                Some(Prefer::Ours) => {}
+                Some(Prefer::Theirs) => merged.push(Merged::Task(theirs.clone())),
+                None => merged.push(Merged::Conflict(None, Some(Box::new(theirs.clone())))),
+            },
+            (None, None) => {}
+        }
+    }
+
+    // Moves on both sides can make a task its own ancestor
+    let mut tasks: Vec<&mut task::Task> = merged
+        .iter_mut()
+        .filter_map(|merged| match merged {
+            Merged::Task(task) => Some(task),
+            Merged::Conflict(..) => None,
+        })
+        .collect();
+    task::break_parent_cycles(&mut tasks);
+
+    merged
+}
+
+// Merge `base`, `ours` and `theirs` the way git's merge drivers do: the result is written over
+// `ours`.  Returns the number of tasks that could not be merged and were written with conflict
+// markers.
+pub fn merge_files(
+    base: &str,
+    ours: &str,
+    theirs: &str,
+    prefer: Option<Prefer>,
+) -> Result<usize, TaskListError> {
+    let load = |file| task_list::create_from_file(file, Some("text"));
+    let (base, ours_list, theirs) = (load(base)?, load(ours)?, load(theirs)?);
+    let merged = merge(base.tasks(), ours_list.tasks(), theirs.tasks(), prefer);
+
+    // Keep removals from both sides, unless the task was merged back in
+    let mut tombstones: BTreeMap<String, f64> = ours_list.tombstones();
+    for (id, timestamp) in theirs.tombstones() {
+        let entry = tombstones.entry(id).or_insert(timestamp);
+        *entry = entry.max(timestamp);
+    }
+    for merged in &merged {
+        if let Merged::Task(task) = merged {
+            tombstones.remove(task.id());
+        }
+    }
+
+    let mut file = BufWriter::new(File::create(ours)?);
+    for path in ours_list.includes() {
+        writeln!(file, "@include {}", path)?;
+    }
+    if ours_list.synced() || theirs.synced() {
+        writeln!(file, "@synced")?;
+    }
+    for (id, timestamp) in &tombstones {
+        writeln!(file, "@deleted {} {}", id, timestamp)?;
+    }
+    // Numbers used on either side are not handed out again
+    let next_id = match (ours_list.next_id(), theirs.next_id()) {
+        (Some(ours), Some(theirs)) => Some(ours.max(theirs)),
+        (ours, theirs) => ours.or(theirs),
+    };
+    if let Some(next_id) = next_id {
+        writeln!(file, "@id-scheme sequential")?;
+        writeln!(file, "@next-id {}", next_id)?;
+    }
+    let mut conflicts = 0;
+    for merged in &merged {
+        match merged {
+            Merged::Task(task) => writeln!(file, "{}", task.to_file_string())?,
+            Merged::Conflict(ours, theirs) => {
+                conflicts += 1;
+                writeln!(file, "<<<<<<< ours")?;
+                if let Some(ours) = ours {
+                    writeln!(file, "{}", ours.to_file_string())?;
+                }
+                writeln!(file, "=======")?;
+                if let Some(theirs) = theirs {
+                    writeln!(file, "{}", theirs.to_file_string())?;
+                }
+                writeln!(file, ">>>>>>> theirs")?;
+            }
+        }
+    }
+    file.flush()?;
+
+    Ok(conflicts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::temp_file;
+    use std::fs;
+
+    fn base() -> task::Task {
+        task::create_from_file_string(r#"Write docs | {"id":"a","timestamp":1.0,"tags":["docs"]}"#)
+            .unwrap()
+    }
+
+    fn merged_task(merged: Vec<Merged>) -> task::Task {
+        match merged.as_slice() {
+            [Merged::Task(task)] => task.clone(),
+            _ => panic!("expected a single merged task"),
+        }
+    }
+
+    #[test]
+    fn changes_to_different_fields_are_merged() {
+        let (mut ours, mut theirs) = (base(), base());
+        ours.set_desc("Write more docs");
+        theirs.set_parent_id(Some("p"));
+        theirs.set_due(Some("2024-05-01"));
+
+        let task = merged_task(merge(&[base()], &[ours], &[theirs], None));
+        assert_eq!(task.desc(), "Write more docs");
+        assert_eq!(task.parent_id().as_deref(), Some("p"));
+        assert_eq!(task.due(), Some("2024-05-01"));
+    }
+
+    #[test]
+    fn tags_are_merged_as_sets() {
+        let (mut ours, mut theirs) = (base(), base());
+        ours.remove_tag("docs");
+        ours.add_tag("urgent");
+        theirs.add_tag("later");
+
+        let task = merged_task(merge(&[base()], &[ours], &[theirs], None));
+        assert_eq!(task.tags(), &["urgent", "later"]);
+    }
+
+    #[test]
+    fn conflicting_changes() {
+        let (mut ours, mut theirs) = (base(), base());
+        ours.set_desc("Ours");
+        theirs.set_desc("Theirs");
+
+        let merged = merge(&[base()], &[ours.clone()], &[theirs.clone()], None);
+        assert!(matches!(merged.as_slice(), [Merged::Conflict(Some(_), Some(_))]));
+
+        let prefer = Some(Prefer::Theirs);
+        assert_eq!(
+            merged_task(merge(&[base()], &[ours.clone()], &[theirs.clone()], prefer)).desc(),
+            "Theirs"
+        );
+        let prefer = Some(Prefer::Ours);
+        assert_eq!(merged_task(merge(&[base()], &[ours], &[theirs], prefer)).desc(), "Ours");
+    }
+
+    #[test]
+    fn later_completion_wins() {
+        let (mut ours, mut theirs) = (base(), base());
+        ours.set_completed_timestamp(100.0);
+        theirs.set_completed_timestamp(200.0);
+
+        let task = merged_task(merge(&[base()], &[ours], &[theirs], None));
+        assert_eq!(task.completed_timestamp(), 200.0);
+    }
+
+    #[test]
+    fn moves_that_form_a_cycle_are_undone() {
+        let tasks = |a: Option<&str>, b: Option<&str>| {
+            vec![task::create(a, Some("a"), "A"), task::create(b, Some("b"), "B")]
+        };
+        let merged =
+            merge(&tasks(None, None), &tasks(Some("b"), None), &tasks(None, Some("a")), None);
+
+        let parents: Vec<Option<String>> = merged
+            .iter()
+            .map(|merged| match merged {
+                Merged::Task(task) => task.parent_id().clone(),
+                Merged::Conflict(..) => panic!("expected merged tasks"),
+            })
+            .collect();
+        assert_eq!(parents, [None, Some("a".to_string())]);
+    }
+
+    #[test]
+    fn additions_and_removals() {
+        let added = vec![task::create(None, Some("b"), "Added")];
+
+        // Removed on one side, unchanged on the other
+        assert!(merge(&[base()], &[], &[base()], None).is_empty());
+        // Added on one side
+        assert!(merged_task(merge(&[], &[], &added, None)) == added[0]);
+
+        // Removed on one side, changed on the other
+        let mut changed = vec![base()];
+        changed[0].set_desc("Changed");
+        let merged = merge(&[base()], &changed, &[], None);
+        assert!(matches!(merged.as_slice(), [Merged::Conflict(Some(_), None)]));
+        assert!(merged_task(merge(&[base()], &changed, &[], Some(Prefer::Ours))) == changed[0]);
+        assert!(merge(&[base()], &changed, &[], Some(Prefer::Theirs)).is_empty());
+    }
+
+    // Merge files with the given headers, each holding the base task, and return the next id of
+    // the result.
+    fn merged_next_id(name: &str, ours: &str, theirs: &str) -> Option<u64> {
+        let files: Vec<String> = ["base", "ours", "theirs"]
+            .iter()
+            .map(|side| temp_file(&format!("{}-{}.t", name, side)))
+            .collect();
+        let task = base().to_file_string();
+        fs::write(&files[0], format!("{}\n", task)).unwrap();
+        fs::write(&files[1], format!("{}{}\n", ours, task)).unwrap();
+        fs::write(&files[2], format!("{}{}\n", theirs, task)).unwrap();
+
+        assert_eq!(merge_files(&files[0], &files[1], &files[2], None).unwrap(), 0);
+        let next_id = task_list::create_from_file(&files[1], Some("text")).unwrap().next_id();
+        for file in files {
+            fs::remove_file(file).unwrap();
+        }
+        next_id
+    }
+
+    #[test]
+    fn next_id_is_kept_from_either_side() {
+        let sequential = |next_id| format!("@id-scheme sequential\n@next-id {}\n", next_id);
+        assert_eq!(merged_next_id("next-id-none", "", ""), None);
+        assert_eq!(merged_next_id("next-id-ours", &sequential(3), ""), Some(3));
+        assert_eq!(merged_next_id("next-id-theirs", "", &sequential(5)), Some(5));
+        assert_eq!(merged_next_id("next-id-both", &sequential(7), &sequential(5)), Some(7));
+    }
+}
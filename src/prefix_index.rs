@@ -0,0 +1,300 @@
+use std::collections::{HashMap, HashSet};
+
+// Shortest unique prefixes of a set of task ids.
+//
+// Ids are kept sorted, so the ids sharing the longest prefix with an id are always its direct
+// neighbours.  The shortest unique prefix is one character longer than the longest prefix shared
+// with either neighbour.  Building the index is O(n log n), and inserting or removing an id only
+// updates the prefixes of its neighbours.
+//
+// Ids can also be given stable prefixes, which are kept as other ids come and go.  A stable
+// prefix is only replaced by a longer one when two ids end up with the same one, e.g. after
+// syncing with another copy of the task list, and earlier stable prefixes of an id still find it
+// as long as no other id had them.
+#[derive(Clone, Default)]
+pub struct PrefixIndex {
+    // All ids, sorted
+    ids: Vec<String>,
+    // Shortest unique prefix by id
+    prefixes: HashMap<String, String>,
+    // Ids that are always shown in full
+    full_ids: HashSet<String>,
+    // Current stable prefix by id, and the other way around
+    stable: HashMap<String, String>,
+    stable_ids: HashMap<String, String>,
+    // Ids given each stable prefix, now or earlier
+    issued: HashMap<String, Vec<String>>,
+}
+
+pub enum Lookup<'a> {
+    Unique(&'a str),
+    // All ids starting with the prefix
+    Ambiguous(&'a [String]),
+    NotFound,
+}
+
+// Length in bytes of the common prefix of `a` and `b`, on a char boundary.
+fn common_prefix_len(a: &str, b: &str) -> usize {
+    a.char_indices()
+        .zip(b.chars())
+        .find(|((_, ca), cb)| ca != cb)
+        .map_or_else(|| a.len().min(b.len()), |((i, _), _)| i)
+}
+
+impl PrefixIndex {
+    // Create an index from (id, show_full_id) pairs.
+    pub fn new<'a, I>(ids: I) -> PrefixIndex
+    where
+        I: IntoIterator<Item = (&'a str, bool)>,
+    {
+        let mut index = PrefixIndex::default();
+        for (id, show_full_id) in ids {
+            index.ids.push(id.to_string());
+            if show_full_id {
+                index.full_ids.insert(id.to_string());
+            }
+        }
+        index.ids.sort();
+
+        for pos in 0..index.ids.len() {
+            index.update(pos);
+        }
+
+        index
+    }
+
+    pub fn insert(&mut self, id: &str, show_full_id: bool) {
+        let pos = self.ids.partition_point(|other| other.as_str() < id);
+        self.ids.insert(pos, id.to_string());
+        if show_full_id {
+            self.full_ids.insert(id.to_string());
+        }
+
+        // Only the new id and its neighbours are affected
+        for pos in pos.saturating_sub(1)..(pos + 2).min(self.ids.len()) {
+            self.update(pos);
+        }
+    }
+
+    pub fn remove(&mut self, id: &str) {
+        let pos = match self.ids.binary_search_by(|other| other.as_str().cmp(id)) {
+            Ok(pos) => pos,
+            Err(_) => return,
+        };
+        self.ids.remove(pos);
+
+        // Another task may share this id, its prefix is updated below
+        if self.ids.binary_search_by(|other| other.as_str().cmp(id)).is_err() {
+            self.prefixes.remove(id);
+            self.full_ids.remove(id);
+            if let Some(prefix) = self.stable.remove(id) {
+                self.stable_ids.remove(&prefix);
+            }
+            for (end, c) in id.char_indices() {
+                let prefix = &id[..end + c.len_utf8()];
+                if let Some(ids) = self.issued.get_mut(prefix) {
+                    ids.retain(|other| other != id);
+                    if ids.is_empty() {
+                        self.issued.remove(prefix);
+                    }
+                }
+            }
+        }
+
+        // The old neighbours now border each other
+        for pos in pos.saturating_sub(1)..(pos + 1).min(self.ids.len()) {
+            self.update(pos);
+        }
+    }
+
+    pub fn prefix(&self, id: &str) -> Option<&String> {
+        self.stable.get(id).or_else(|| self.prefixes.get(id))
+    }
+
+    // Record the stable prefixes `id` was given before, the last one is current.
+    pub fn set_stable(&mut self, id: &str, prefixes: &[String]) {
+        for prefix in prefixes {
+            let ids = self.issued.entry(prefix.to_string()).or_default();
+            if !ids.iter().any(|other| other == id) {
+                ids.push(id.to_string());
+            }
+        }
+        if let Some(prefix) = prefixes.last() {
+            self.set_current(id, prefix);
+        }
+    }
+
+    // Give `id` a stable prefix if it has none, or another id has the same one.  The new prefix
+    // is at least as long as the shortest unique prefix and longer than the one it replaces.
+    pub fn issue(&mut self, id: &str) -> Option<String> {
+        if self.full_ids.contains(id) {
+            return None;
+        }
+        let current = self.stable.get(id).cloned();
+        let owner = current.as_ref().and_then(|prefix| self.stable_ids.get(prefix));
+        if owner.is_some_and(|owner| owner == id) {
+            return None;
+        }
+
+        let chars = |prefix: &String| prefix.chars().count();
+        let mut len = self.prefixes.get(id).map_or(0, chars);
+        if let Some(current) = &current {
+            len = len.max(chars(current) + 1);
+        }
+        let mut prefix: String = id.chars().take(len).collect();
+        while self.stable_ids.contains_key(&prefix) && prefix.len() < id.len() {
+            len += 1;
+            prefix = id.chars().take(len).collect();
+        }
+
+        self.issued.entry(prefix.clone()).or_default().push(id.to_string());
+        self.set_current(id, &prefix);
+        Some(prefix)
+    }
+
+    fn set_current(&mut self, id: &str, prefix: &str) {
+        if let Some(old) = self.stable.insert(id.to_string(), prefix.to_string()) {
+            if self.stable_ids.get(&old).is_some_and(|other| other == id) {
+                self.stable_ids.remove(&old);
+            }
+        }
+        // The first id given a prefix keeps it, `issue` gives the others new ones
+        self.stable_ids.entry(prefix.to_string()).or_insert_with(|| id.to_string());
+    }
+
+    // Find the id matching `prefix`.  An exact match always wins, even if it is also the prefix
+    // of other ids, then the id with that stable prefix now or, if only one had it, earlier.
+    pub fn lookup(&self, prefix: &str) -> Lookup<'_> {
+        let start = self.ids.partition_point(|id| id.as_str() < prefix);
+        let end = start + self.ids[start..].partition_point(|id| id.starts_with(prefix));
+        let matches = &self.ids[start..end];
+
+        if let [id, ..] = matches {
+            if id != prefix {
+                if let Some(id) = self.stable_ids.get(prefix) {
+                    return Lookup::Unique(id);
+                }
+                if let Some([id]) = self.issued.get(prefix).map(|ids| ids.as_slice()) {
+                    return Lookup::Unique(id);
+                }
+            }
+        }
+
+        match matches {
+            [] => Lookup::NotFound,
+            [id] => Lookup::Unique(id),
+            [id, ..] if id == prefix => Lookup::Unique(id),
+            _ => Lookup::Ambiguous(matches),
+        }
+    }
+
+    fn update(&mut self, pos: usize) {
+        let id = &self.ids[pos];
+
+        let prefix = if self.full_ids.contains(id) {
+            id.to_string()
+        } else {
+            let mut len = 0;
+            if pos > 0 {
+                len = len.max(common_prefix_len(id, &self.ids[pos - 1]));
+            }
+            if pos + 1 < self.ids.len() {
+                len = len.max(common_prefix_len(id, &self.ids[pos + 1]));
+            }
+
+            // One more character than is shared with any other id
+            match id[len..].chars().next() {
+                Some(c) => id[..len + c.len_utf8()].to_string(),
+                None => id.to_string(),
+            }
+        };
+
+        self.prefixes.insert(id.to_string(), prefix);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shortest_unique_prefixes() {
+        let index =
+            PrefixIndex::new(vec![("abc", false), ("abd", false), ("b12", false), ("c", false)]);
+        assert_eq!(index.prefix("abc").unwrap(), "abc");
+        assert_eq!(index.prefix("abd").unwrap(), "abd");
+        assert_eq!(index.prefix("b12").unwrap(), "b");
+        assert_eq!(index.prefix("c").unwrap(), "c");
+    }
+
+    #[test]
+    fn full_ids_are_shown_in_full() {
+        let index = PrefixIndex::new(vec![("custom", true), ("d00d", false)]);
+        assert_eq!(index.prefix("custom").unwrap(), "custom");
+        assert_eq!(index.prefix("d00d").unwrap(), "d");
+    }
+
+    #[test]
+    fn insert_and_remove_update_neighbours() {
+        let mut index = PrefixIndex::new(vec![("abc", false), ("b12", false)]);
+        assert_eq!(index.prefix("abc").unwrap(), "a");
+
+        index.insert("abd", false);
+        assert_eq!(index.prefix("abc").unwrap(), "abc");
+        assert_eq!(index.prefix("abd").unwrap(), "abd");
+
+        index.remove("abd");
+        assert_eq!(index.prefix("abc").unwrap(), "a");
+        assert!(index.prefix("abd").is_none());
+    }
+
+    #[test]
+    fn lookup() {
+        let index = PrefixIndex::new(vec![("abc", false), ("abcd", false), ("b12", false)]);
+        assert!(matches!(index.lookup("b"), Lookup::Unique("b12")));
+        // An exact match wins over longer ids
+        assert!(matches!(index.lookup("abc"), Lookup::Unique("abc")));
+        assert!(matches!(index.lookup("ab"), Lookup::Ambiguous(ids) if ids.len() == 2));
+        assert!(matches!(index.lookup("x"), Lookup::NotFound));
+        assert!(matches!(index.lookup("abce"), Lookup::NotFound));
+    }
+
+    #[test]
+    fn issued_prefixes_are_kept_as_ids_are_added() {
+        let mut index = PrefixIndex::new(vec![("abc", false), ("b12", false)]);
+        assert_eq!(index.issue("abc").as_deref(), Some("a"));
+        assert_eq!(index.issue("abc"), None);
+
+        index.insert("abd", false);
+        assert_eq!(index.prefix("abc").unwrap(), "a");
+        assert!(matches!(index.lookup("a"), Lookup::Unique("abc")));
+        assert_eq!(index.issue("abd").as_deref(), Some("abd"));
+    }
+
+    #[test]
+    fn issue_lengthens_a_prefix_given_to_two_ids() {
+        // As after syncing two lists that each gave their own task "a"
+        let mut index = PrefixIndex::new(vec![("abc", false), ("axy", false)]);
+        index.set_stable("abc", &["a".to_string()]);
+        index.set_stable("axy", &["a".to_string()]);
+
+        assert_eq!(index.issue("abc"), None);
+        assert_eq!(index.issue("axy").as_deref(), Some("ax"));
+        assert!(matches!(index.lookup("a"), Lookup::Unique("abc")));
+        assert!(matches!(index.lookup("ax"), Lookup::Unique("axy")));
+    }
+
+    #[test]
+    fn earlier_stable_prefixes_still_find_the_id() {
+        let mut index = PrefixIndex::new(vec![("abc", false), ("b12", false)]);
+        index.set_stable("abc", &["a".to_string(), "ab".to_string()]);
+        assert_eq!(index.prefix("abc").unwrap(), "ab");
+        assert!(matches!(index.lookup("a"), Lookup::Unique("abc")));
+    }
+
+    #[test]
+    fn full_ids_are_not_issued_prefixes() {
+        let mut index = PrefixIndex::new(vec![("custom", true)]);
+        assert_eq!(index.issue("custom"), None);
+    }
+}
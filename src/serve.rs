@@ -0,0 +1,559 @@
+use super::filter::Filter;
+use super::task;
+use super::task_list::{TaskList, TaskListError};
+use serde_json::{json, Value};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+// Larger HTTP requests are refused
+const MAX_BODY: usize = 1 << 20;
+
+// HTTP clients that stop sending are disconnected after this long
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+// JSON-RPC error codes, errors from the task list use their exit status instead
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+// Where `t serve` listens for clients.
+pub enum Address {
+    // JSON-RPC requests and responses one per line
+    Socket(String),
+    // JSON-RPC requests POSTed to localhost over HTTP
+    Port(u16),
+}
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: &str) -> RpcError {
+        RpcError { code, message: message.to_string() }
+    }
+}
+
+impl From<TaskListError> for RpcError {
+    fn from(error: TaskListError) -> RpcError {
+        RpcError { code: error.exit_code() as i64, message: error.to_string() }
+    }
+}
+
+// The task list being served and how to load it again when its files are changed by something
+// else, e.g. an editor or another t.
+struct Server {
+    tasks: TaskList,
+    mtimes: Vec<Option<SystemTime>>,
+    reload: Box<dyn Fn() -> Result<TaskList, TaskListError> + Send>,
+}
+
+impl Server {
+    // The task list, loaded again first if any of its files changed since it was loaded or saved.
+    fn tasks(&mut self) -> Result<&mut TaskList, TaskListError> {
+        if mtimes(&self.tasks) != self.mtimes {
+            let mut tasks = (self.reload)()?;
+            tasks.set_quiet(true);
+            self.mtimes = mtimes(&tasks);
+            self.tasks = tasks;
+        }
+        Ok(&mut self.tasks)
+    }
+}
+
+// When each of the files of `tasks` was last modified.
+fn mtimes(tasks: &TaskList) -> Vec<Option<SystemTime>> {
+    let mtime = |file: &str| std::fs::metadata(file).and_then(|metadata| metadata.modified()).ok();
+    tasks.files().into_iter().map(mtime).collect()
+}
+
+// Keep `tasks` loaded and answer JSON-RPC requests for them until killed.  Each client is served
+// on its own thread, one request at a time is run against the task list and every change is saved
+// before the response is sent.  The task list is loaded again with `reload` before a request if its
+// files have changed since.
+pub fn serve(
+    mut tasks: TaskList,
+    address: &Address,
+    reload: impl Fn() -> Result<TaskList, TaskListError> + Send + 'static,
+) -> Result<(), TaskListError> {
+    let quiet = tasks.quiet();
+    tasks.set_quiet(true);
+    let mtimes = mtimes(&tasks);
+    let tasks = Arc::new(Mutex::new(Server { tasks, mtimes, reload: Box::new(reload) }));
+
+    match address {
+        Address::Socket(path) => serve_socket(tasks, path, quiet),
+        Address::Port(port) => {
+            let listener = TcpListener::bind(("127.0.0.1", *port))?;
+            if !quiet {
+                println!("listening on http://{}", listener.local_addr()?);
+            }
+            let port = listener.local_addr()?.port();
+            for stream in listener.incoming().flatten() {
+                let tasks = Arc::clone(&tasks);
+                thread::spawn(move || serve_http(&tasks, stream, port));
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(unix)]
+fn serve_socket(tasks: Arc<Mutex<Server>>, path: &str, quiet: bool) -> Result<(), TaskListError> {
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    // A socket left behind by a server that is no longer running is replaced
+    let stale = std::fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket())
+        && UnixStream::connect(path).is_err();
+    if stale {
+        std::fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    if !quiet {
+        println!("listening on {}", path);
+    }
+    for stream in listener.incoming().flatten() {
+        let tasks = Arc::clone(&tasks);
+        thread::spawn(move || serve_lines(&tasks, stream));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn serve_socket(_: Arc<Mutex<Server>>, _: &str, _: bool) -> Result<(), TaskListError> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "sockets are only available on Unix").into())
+}
+
+// Answer requests, one per line, until the client disconnects.
+#[cfg(unix)]
+fn serve_lines(tasks: &Mutex<Server>, stream: std::os::unix::net::UnixStream) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some(response) = respond(tasks, &line) {
+            writeln!(writer, "{}", response)?;
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+// Answer a single HTTP request.  Web pages can send requests to localhost too, so requests with an
+// Origin, requests for other hosts (DNS rebinding) and requests that aren't JSON, which browsers
+// send without asking first, are refused.
+fn serve_http(tasks: &Mutex<Server>, mut stream: TcpStream, port: u16) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    let mut length = 0;
+    let mut host = None;
+    let mut origin = false;
+    let mut json = false;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "content-length" => length = value.parse().unwrap_or(0),
+                "content-type" => {
+                    let media_type = value.split(';').next().unwrap_or("").trim();
+                    json = media_type.eq_ignore_ascii_case("application/json");
+                }
+                "host" => host = Some(value.to_ascii_lowercase()),
+                "origin" => origin = true,
+                _ => {}
+            }
+        }
+    }
+
+    if !request_line.starts_with("POST ") {
+        return write_http(&mut stream, "405 Method Not Allowed", "POST JSON-RPC requests\n");
+    }
+    let hosts = [format!("127.0.0.1:{}", port), format!("localhost:{}", port)];
+    if origin || !host.is_some_and(|host| hosts.contains(&host)) {
+        return write_http(&mut stream, "403 Forbidden", "only local clients are served\n");
+    }
+    if !json {
+        return write_http(
+            &mut stream,
+            "415 Unsupported Media Type",
+            "expected application/json\n",
+        );
+    }
+    if length > MAX_BODY {
+        return write_http(&mut stream, "413 Payload Too Large", "");
+    }
+
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    match respond(tasks, &String::from_utf8_lossy(&body)) {
+        Some(response) => write_http(&mut stream, "200 OK", &response.to_string()),
+        None => write_http(&mut stream, "204 No Content", ""),
+    }
+}
+
+fn write_http(stream: &mut TcpStream, status: &str, body: &str) -> io::Result<()> {
+    let content_type = if status.starts_with("200") { "application/json" } else { "text/plain" };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+// The response to a request or a batch of requests, None if there is nothing to answer, as for
+// notifications.
+fn respond(tasks: &Mutex<Server>, request: &str) -> Option<Value> {
+    let request: Value = match serde_json::from_str(request) {
+        Ok(request) => request,
+        Err(e) => {
+            return Some(response(Value::Null, Err(RpcError::new(PARSE_ERROR, &e.to_string()))))
+        }
+    };
+
+    match request {
+        Value::Array(batch) if !batch.is_empty() => {
+            let responses: Vec<Value> =
+                batch.iter().filter_map(|request| respond_one(tasks, request)).collect();
+            if responses.is_empty() {
+                None
+            } else {
+                Some(Value::Array(responses))
+            }
+        }
+        request => respond_one(tasks, &request),
+    }
+}
+
+fn respond_one(tasks: &Mutex<Server>, request: &Value) -> Option<Value> {
+    let method = match request.get("method").and_then(Value::as_str) {
+        Some(method) => method,
+        None => {
+            let id = request.get("id").cloned().unwrap_or(Value::Null);
+            return Some(response(id, Err(RpcError::new(INVALID_REQUEST, "expected a method"))));
+        }
+    };
+    let params = request.get("params").unwrap_or(&Value::Null);
+    let result = call(&mut lock(tasks), method, params);
+
+    // Requests without an id are notifications
+    request.get("id").map(|id| response(id.clone(), result))
+}
+
+fn response(id: Value, result: Result<Value, RpcError>) -> Value {
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        Err(error) => json!({
+            "jsonrpc": "2.0",
+            "error": { "code": error.code, "message": error.message },
+            "id": id,
+        }),
+    }
+}
+
+// The server, even if a thread panicked while holding it.
+fn lock(tasks: &Mutex<Server>) -> MutexGuard<'_, Server> {
+    tasks.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn call(server: &mut Server, method: &str, params: &Value) -> Result<Value, RpcError> {
+    let tasks = server.tasks()?;
+    match method {
+        "list" => list(tasks, params),
+        "get" => {
+            let id = tasks.get_full_id(string(params, "id")?)?;
+            Ok(task_json(tasks, &id))
+        }
+        "add" | "edit" | "complete" | "tag" | "move" | "remove" => {
+            // Changes are saved right away, or undone if they can't be
+            let snapshot = tasks.clone();
+            let result = change(tasks, method, params).and_then(|result| {
+                tasks.save()?;
+                Ok(result)
+            });
+            match result {
+                Ok(_) => server.mtimes = mtimes(&server.tasks),
+                Err(_) => *tasks = snapshot,
+            }
+            result
+        }
+        _ => Err(RpcError::new(METHOD_NOT_FOUND, &format!("unknown method '{}'", method))),
+    }
+}
+
+// All tasks, or those matching `where`, oldest first.
+fn list(tasks: &TaskList, params: &Value) -> Result<Value, RpcError> {
+    let mut selected: Vec<&task::Task> = match optional_string(params, "where")? {
+        Some(filter) => {
+            let ids = Filter::parse(filter)?.select(tasks)?;
+            ids.iter().filter_map(|id| tasks.task_by_id(id)).collect()
+        }
+        None => tasks.tasks().iter().collect(),
+    };
+    if flag(params, "hide_completed")? {
+        selected.retain(|task| !task.is_completed());
+    }
+    selected.sort_by(|a, b| a.timestamp().partial_cmp(&b.timestamp()).unwrap());
+
+    Ok(selected.iter().map(|task| task_json(tasks, task.id())).collect())
+}
+
+// Make a change and return the task changed, as it was before if it was removed.
+fn change(tasks: &mut TaskList, method: &str, params: &Value) -> Result<Value, RpcError> {
+    if method == "add" {
+        let desc = string(params, "desc")?.replace('\n', "");
+        let parent = optional_string(params, "parent")?;
+        let id = tasks.add_task(parent, optional_string(params, "id")?, &desc)?;
+        return Ok(task_json(tasks, &id));
+    }
+
+    let id = tasks.get_full_id(string(params, "id")?)?;
+    match method {
+        "edit" => tasks.get_task(&id)?.set_desc(&string(params, "desc")?.replace('\n', "")),
+        "complete" => tasks.complete_task(&id, flag(params, "force")?)?,
+        "tag" => {
+            let tags = match params.get("tags").and_then(Value::as_array) {
+                Some(tags) => tags,
+                None => {
+                    return Err(invalid_params("tags must be a list like [\"+tag\", \"-tag\"]"))
+                }
+            };
+            let task = tasks.get_task(&id)?;
+            for tag in tags {
+                let tag = tag.as_str().ok_or_else(|| invalid_params("tags must be strings"))?;
+                match tag.strip_prefix('-') {
+                    Some(tag) => task.remove_tag(tag),
+                    None => task.add_tag(tag.trim_start_matches('+')),
+                }
+            }
+        }
+        "move" => tasks.move_task(&id, optional_string(params, "parent")?)?,
+        _ => {
+            let removed = task_json(tasks, &id);
+            tasks.remove_task(&id, flag(params, "force")?)?;
+            return Ok(removed);
+        }
+    }
+
+    Ok(task_json(tasks, &id))
+}
+
+// Task `id` with its prefix, or null if there is no such task.
+fn task_json(tasks: &TaskList, id: &str) -> Value {
+    match tasks.task_by_id(id) {
+        Some(task) => {
+            let mut json = task.to_json_value();
+            json["prefix"] = tasks.prefix(id).map_or(id, |prefix| prefix.as_str()).into();
+            json
+        }
+        None => Value::Null,
+    }
+}
+
+fn invalid_params(message: &str) -> RpcError {
+    RpcError::new(INVALID_PARAMS, message)
+}
+
+fn string<'a>(params: &'a Value, name: &str) -> Result<&'a str, RpcError> {
+    optional_string(params, name)?.ok_or_else(|| invalid_params(&format!("missing '{}'", name)))
+}
+
+fn optional_string<'a>(params: &'a Value, name: &str) -> Result<Option<&'a str>, RpcError> {
+    match params.get(name) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(value)) => Ok(Some(value)),
+        Some(_) => Err(invalid_params(&format!("'{}' must be a string", name))),
+    }
+}
+
+fn flag(params: &Value, name: &str) -> Result<bool, RpcError> {
+    match params.get(name) {
+        None | Some(Value::Null) => Ok(false),
+        Some(Value::Bool(value)) => Ok(*value),
+        Some(_) => Err(invalid_params(&format!("'{}' must be true or false", name))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::temp_file;
+    use crate::task_list;
+    use std::fs;
+
+    fn server(file: &str) -> Mutex<Server> {
+        let load = |file: &str| {
+            let mut tasks = task_list::create_from_file(file, Some("text"))?;
+            tasks.set_quiet(true);
+            Ok(tasks)
+        };
+        let tasks = load(file).unwrap();
+        let mtimes = mtimes(&tasks);
+        let file = file.to_string();
+        Mutex::new(Server { tasks, mtimes, reload: Box::new(move || load(&file)) })
+    }
+
+    // The result of calling `method`, or the error code.
+    fn rpc(server: &Mutex<Server>, method: &str, params: Value) -> Result<Value, i64> {
+        let request = json!({ "jsonrpc": "2.0", "method": method, "params": params, "id": 1 });
+        let response = respond(server, &request.to_string()).unwrap();
+        assert_eq!(response["id"], 1);
+        match response.get("error") {
+            Some(error) => Err(error["code"].as_i64().unwrap()),
+            None => Ok(response["result"].clone()),
+        }
+    }
+
+    #[test]
+    fn methods_change_and_save_tasks() {
+        let file = temp_file("serve.t");
+        let server = server(&file);
+
+        let a = rpc(&server, "add", json!({ "desc": "A", "id": "a" })).unwrap();
+        assert_eq!((&a["id"], &a["desc"], &a["prefix"]), (&json!("a"), &json!("A"), &json!("a")));
+        rpc(&server, "add", json!({ "desc": "B\nb", "id": "b", "parent": "a" })).unwrap();
+        assert_eq!(rpc(&server, "get", json!({ "id": "b" })).unwrap()["desc"], "Bb");
+
+        rpc(&server, "edit", json!({ "id": "a", "desc": "A2" })).unwrap();
+        let tagged = rpc(&server, "tag", json!({ "id": "b", "tags": ["+x", "y", "-x"] })).unwrap();
+        assert_eq!(tagged["tags"], json!(["y"]));
+        let moved = rpc(&server, "move", json!({ "id": "b" })).unwrap();
+        assert!(moved.get("parent_id").is_none_or(Value::is_null));
+        let completed = rpc(&server, "complete", json!({ "id": "b" })).unwrap();
+        assert!(completed["completed_timestamp"].as_f64().unwrap() > 0.0);
+
+        let list = rpc(&server, "list", json!({})).unwrap();
+        assert_eq!(list.as_array().unwrap().len(), 2);
+        let list = rpc(&server, "list", json!({ "hide_completed": true })).unwrap();
+        assert_eq!(list[0]["id"], "a");
+        assert_eq!(list.as_array().unwrap().len(), 1);
+
+        assert_eq!(rpc(&server, "remove", json!({ "id": "a" })).unwrap()["desc"], "A2");
+        assert_eq!(rpc(&server, "get", json!({ "id": "a" })), Err(4));
+
+        // Every change was saved
+        let saved = task_list::create_from_file(&file, Some("text")).unwrap();
+        let b = saved.task_by_id("b").unwrap();
+        assert!(b.is_completed() && b.parent_id().is_none() && saved.task_by_id("a").is_none());
+
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn bad_requests_are_errors() {
+        let file = temp_file("serve-errors.t");
+        let server = server(&file);
+
+        assert_eq!(rpc(&server, "nope", json!({})), Err(METHOD_NOT_FOUND));
+        assert_eq!(rpc(&server, "add", json!({})), Err(INVALID_PARAMS));
+        assert_eq!(rpc(&server, "add", json!({ "desc": 1 })), Err(INVALID_PARAMS));
+        assert_eq!(rpc(&server, "tag", json!({ "id": "x", "tags": "x" })), Err(4));
+        let response = respond(&server, "{").unwrap();
+        assert_eq!(response["error"]["code"], PARSE_ERROR);
+        let response = respond(&server, r#"{"id": 2}"#).unwrap();
+        assert_eq!(response["error"]["code"], INVALID_REQUEST);
+
+        // Notifications are run but not answered, and neither are batches of them
+        let notification = json!({ "method": "add", "params": { "desc": "A", "id": "a" } });
+        assert!(respond(&server, &notification.to_string()).is_none());
+        assert!(respond(&server, &json!([notification]).to_string()).is_none());
+        let batch = json!([
+            { "method": "get", "params": { "id": "a" }, "id": 1 },
+            { "method": "nope", "id": 2 },
+        ]);
+        let responses = respond(&server, &batch.to_string()).unwrap();
+        assert_eq!(responses[0]["result"]["desc"], "A");
+        assert_eq!(responses[1]["error"]["code"], METHOD_NOT_FOUND);
+
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn tasks_are_loaded_again_when_changed_by_something_else() {
+        let file = temp_file("serve-reload.t");
+        let server = server(&file);
+        rpc(&server, "add", json!({ "desc": "A", "id": "a" })).unwrap();
+
+        let mut other = task_list::create_from_file(&file, Some("text")).unwrap();
+        other.set_quiet(true);
+        other.add_task(None, Some("b"), "B").unwrap();
+        other.save().unwrap();
+        // Saved in the same clock tick the file would look unchanged
+        let later = SystemTime::now() + Duration::from_secs(60);
+        fs::File::options().write(true).open(&file).unwrap().set_modified(later).unwrap();
+
+        assert_eq!(rpc(&server, "list", json!({})).unwrap().as_array().unwrap().len(), 2);
+
+        fs::remove_file(file).unwrap();
+    }
+
+    // The status line of the response to an HTTP `request`, sent with the Host set to `host`.
+    fn http(server: &Mutex<Server>, request: &str, host: &str) -> String {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let request = request.replace("{host}", &host.replace("{port}", &port.to_string()));
+        client.write_all(request.as_bytes()).unwrap();
+
+        let (stream, _) = listener.accept().unwrap();
+        serve_http(server, stream, port).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        response.lines().next().unwrap().to_string()
+    }
+
+    #[test]
+    fn only_local_json_requests_are_served() {
+        let file = temp_file("serve-http.t");
+        let server = server(&file);
+        let post = |headers: &str, body: &str| {
+            format!(
+                "POST / HTTP/1.1\r\nHost: {{host}}\r\n{}Content-Length: {}\r\n\r\n{}",
+                headers,
+                body.len(),
+                body
+            )
+        };
+        let json = "Content-Type: application/json; charset=utf-8\r\n";
+        let get = r#"{"jsonrpc": "2.0", "method": "get", "params": {"id": "x"}, "id": 1}"#;
+        let local = "127.0.0.1:{port}";
+
+        assert_eq!(http(&server, &post(json, get), local), "HTTP/1.1 200 OK");
+        assert_eq!(http(&server, &post(json, get), "LOCALHOST:{port}"), "HTTP/1.1 200 OK");
+        let notification = r#"{"jsonrpc": "2.0", "method": "list"}"#;
+        assert_eq!(http(&server, &post(json, notification), local), "HTTP/1.1 204 No Content");
+
+        let get_request = "GET / HTTP/1.1\r\nHost: {host}\r\n\r\n";
+        assert_eq!(http(&server, get_request, local), "HTTP/1.1 405 Method Not Allowed");
+        let origin = format!("{}Origin: http://example.com\r\n", json);
+        assert_eq!(http(&server, &post(&origin, ""), local), "HTTP/1.1 403 Forbidden");
+        assert_eq!(http(&server, &post(json, ""), "example.com:{port}"), "HTTP/1.1 403 Forbidden");
+        assert_eq!(http(&server, &post(json, ""), "127.0.0.1:1"), "HTTP/1.1 403 Forbidden");
+        let text = "Content-Type: text/plain\r\n";
+        assert_eq!(http(&server, &post(text, ""), local), "HTTP/1.1 415 Unsupported Media Type");
+        let too_long = format!("{}Content-Length: {}\r\n", json, MAX_BODY + 1);
+        let request = format!("POST / HTTP/1.1\r\nHost: {{host}}\r\n{}\r\n", too_long);
+        assert_eq!(http(&server, &request, local), "HTTP/1.1 413 Payload Too Large");
+
+        let _ = fs::remove_file(file);
+    }
+}
@@ -0,0 +1,179 @@
+use super::task_list::{TaskList, TaskListError};
+use super::{get_args, print_completions, run_command};
+use clap::AppSettings;
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+
+// Subcommands that only run from the command line, they are refused inside the shell
+pub const NOT_IN_SHELL: &[&str] = &["merge-driver", "serve", "shell"];
+
+// Names of the subcommands to complete, as defined for the command line, and `exit`.
+fn subcommands() -> Vec<String> {
+    let mut names: Vec<String> = get_args()
+        .p
+        .subcommands
+        .iter()
+        .filter(|subcommand| !subcommand.p.is_set(AppSettings::Hidden))
+        .map(|subcommand| subcommand.p.meta.name.to_string())
+        .filter(|name| !NOT_IN_SHELL.contains(&name.as_str()))
+        .chain(["exit".to_string()])
+        .collect();
+    names.sort();
+    names
+}
+
+// Tab completion of subcommands and task prefixes.
+struct ShellHelper {
+    subcommands: Vec<String>,
+    // (prefix, description) of every task
+    tasks: Vec<(String, String)>,
+}
+
+impl Completer for ShellHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let start = line[..pos].rfind(char::is_whitespace).map_or(0, |i| i + 1);
+        let word = &line[start..pos];
+
+        let candidates = if line[..start].trim().is_empty() {
+            self.subcommands
+                .iter()
+                .filter(|name| name.starts_with(word))
+                .map(|name| Pair { display: name.to_string(), replacement: name.to_string() })
+                .collect()
+        } else if word.starts_with('-') {
+            Vec::new()
+        } else {
+            self.tasks
+                .iter()
+                .filter(|(prefix, _)| prefix.starts_with(word))
+                .map(|(prefix, desc)| Pair {
+                    display: format!("{}: {}", prefix, desc),
+                    replacement: prefix.to_string(),
+                })
+                .collect()
+        };
+
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
+
+fn task_candidates(tasks: &TaskList) -> Vec<(String, String)> {
+    tasks
+        .tasks()
+        .iter()
+        .map(|task| {
+            let prefix = tasks.prefix(task.id()).unwrap_or(task.id());
+            (prefix.to_string(), task.desc().to_string())
+        })
+        .collect()
+}
+
+// Read commands in a loop and run them against `tasks`, using the same syntax as the command
+// line.  The task list is saved after every command that changes it.
+pub fn run(tasks: &mut TaskList) -> Result<(), TaskListError> {
+    let mut editor: Editor<ShellHelper, DefaultHistory> = match Editor::new() {
+        Ok(editor) => editor,
+        Err(e) => return Err(readline_error(e)),
+    };
+    editor.set_helper(Some(ShellHelper { subcommands: subcommands(), tasks: Vec::new() }));
+
+    loop {
+        if let Some(helper) = editor.helper_mut() {
+            helper.tasks = task_candidates(tasks);
+        }
+
+        let line = match editor.readline("t> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(readline_error(e)),
+        };
+
+        if line.trim().is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(line.as_str());
+
+        let words = match shell_words::split(&line) {
+            Ok(words) => words,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                continue;
+            }
+        };
+
+        if let [word] = words.as_slice() {
+            if word == "exit" || word == "quit" {
+                break;
+            }
+        }
+
+        // Parse with the command line definition so the syntax is identical.  The task file is
+        // already loaded, pass it along to satisfy the parser.
+        let mut args = vec!["t".to_string(), "--file".to_string(), tasks.file().to_string()];
+        args.extend(words);
+        let matches = match get_args().get_matches_from_safe(args) {
+            Ok(matches) => matches,
+            Err(e) => {
+                println!("{}", e.message);
+                continue;
+            }
+        };
+
+        if let Some(shell) = matches.value_of("completions") {
+            print_completions(shell);
+            continue;
+        }
+
+        if let Err(e) = run_command(tasks, &matches) {
+            eprintln!("Error: {}", e);
+        }
+    }
+
+    Ok(())
+}
+
+fn readline_error(error: ReadlineError) -> TaskListError {
+    match error {
+        ReadlineError::Io(e) => TaskListError::IOError(e),
+        e => TaskListError::IOError(std::io::Error::other(e.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subcommands_are_those_of_the_command_line() {
+        let names = subcommands();
+        for name in ["add", "exit", "id-scheme", "template", "ui"] {
+            assert!(names.contains(&name.to_string()), "{}", name);
+        }
+        for name in ["__complete", "merge-driver", "serve", "shell"] {
+            assert!(!names.contains(&name.to_string()), "{}", name);
+        }
+    }
+}
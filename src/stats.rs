@@ -0,0 +1,262 @@
+use super::task;
+use super::task_list::TaskList;
+use colored::Colorize;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::time::SystemTime;
+
+const SECONDS_PER_DAY: f64 = 24.0 * 60.0 * 60.0;
+const SECONDS_PER_WEEK: f64 = 7.0 * SECONDS_PER_DAY;
+
+#[derive(Serialize)]
+pub struct OpenTask {
+    id: String,
+    prefix: String,
+    desc: String,
+    age_days: f64,
+}
+
+#[derive(Serialize)]
+pub struct Week {
+    // Weeks ago, 0 is the last seven days
+    weeks_ago: usize,
+    created: usize,
+    completed: usize,
+}
+
+#[derive(Serialize)]
+pub struct Stats {
+    total: usize,
+    open: usize,
+    completed: usize,
+    tags: BTreeMap<String, usize>,
+    depth: usize,
+    oldest_open: Vec<OpenTask>,
+    weeks: Vec<Week>,
+    average_lead_time_days: Option<f64>,
+}
+
+fn now() -> f64 {
+    match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        Err(_) => 0.0,
+        Ok(ts) => ts.as_secs_f64(),
+    }
+}
+
+// Depth of a task in the tree, top level tasks have a depth of 1.
+fn task_depth(
+    task: &task::Task,
+    by_id: &HashMap<&str, &task::Task>,
+    depths: &mut HashMap<String, usize>,
+) -> usize {
+    if let Some(depth) = depths.get(task.id()) {
+        return *depth;
+    }
+
+    // A task met again is in a cycle of parents, which is cut where it was entered
+    depths.insert(task.id().to_string(), 1);
+    let depth = match task.parent_id().as_deref().and_then(|id| by_id.get(id)) {
+        Some(parent) => task_depth(parent, by_id, depths) + 1,
+        None => 1,
+    };
+
+    depths.insert(task.id().to_string(), depth);
+    depth
+}
+
+pub fn compute(tasks: &TaskList, weeks: usize, oldest: usize) -> Stats {
+    let now = now();
+    let all_tasks = tasks.tasks();
+
+    let completed = all_tasks.iter().filter(|t| t.is_completed()).count();
+
+    let mut tags = BTreeMap::new();
+    for task in all_tasks {
+        for tag in task.tags() {
+            *tags.entry(tag.to_string()).or_insert(0) += 1;
+        }
+    }
+
+    let by_id: HashMap<&str, &task::Task> =
+        all_tasks.iter().map(|t| (t.id().as_str(), t)).collect();
+    let mut depths = HashMap::new();
+    let depth = all_tasks.iter().map(|t| task_depth(t, &by_id, &mut depths)).max().unwrap_or(0);
+
+    // Tasks created before timestamps were recorded have a timestamp of 0 and are left out of
+    // anything age related.
+    let mut open_tasks: Vec<&task::Task> =
+        all_tasks.iter().filter(|t| !t.is_completed() && t.timestamp() != 0.0).collect();
+    open_tasks.sort_by(|a, b| a.timestamp().partial_cmp(&b.timestamp()).unwrap());
+    let oldest_open = open_tasks
+        .iter()
+        .take(oldest)
+        .map(|t| OpenTask {
+            id: t.id().to_string(),
+            prefix: tasks.prefix(t.id()).unwrap_or(t.id()).to_string(),
+            desc: t.desc().to_string(),
+            age_days: (now - t.timestamp()) / SECONDS_PER_DAY,
+        })
+        .collect();
+
+    let mut weeks: Vec<Week> =
+        (0..weeks).map(|weeks_ago| Week { weeks_ago, created: 0, completed: 0 }).collect();
+    let week_of = |timestamp: f64| {
+        if timestamp == 0.0 || timestamp > now {
+            None
+        } else {
+            Some(((now - timestamp) / SECONDS_PER_WEEK) as usize)
+        }
+    };
+    for task in all_tasks {
+        if let Some(week) = week_of(task.timestamp()).and_then(|w| weeks.get_mut(w)) {
+            week.created += 1;
+        }
+        if let Some(week) = week_of(task.completed_timestamp()).and_then(|w| weeks.get_mut(w)) {
+            week.completed += 1;
+        }
+    }
+
+    let lead_times: Vec<f64> = all_tasks
+        .iter()
+        .filter(|t| t.is_completed() && t.timestamp() != 0.0)
+        .map(|t| (t.completed_timestamp() - t.timestamp()) / SECONDS_PER_DAY)
+        .collect();
+    let average_lead_time_days = if lead_times.is_empty() {
+        None
+    } else {
+        Some(lead_times.iter().sum::<f64>() / lead_times.len() as f64)
+    };
+
+    Stats {
+        total: all_tasks.len(),
+        open: all_tasks.len() - completed,
+        completed,
+        tags,
+        depth,
+        oldest_open,
+        weeks,
+        average_lead_time_days,
+    }
+}
+
+impl Stats {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(&self).unwrap()
+    }
+
+    // Print the statistics, with plain ASCII guides if `ascii` is set.
+    pub fn show(&self, ascii: bool) {
+        let (line, last) = if ascii { ("|", "`") } else { ("│", "└") };
+
+        println!("Tasks:");
+        println!("{} total:     {}", line, self.total);
+        println!("{} open:      {}", line, self.open);
+        println!("{} completed: {}", line, self.completed);
+        println!("{} depth:     {}", line, self.depth);
+        match self.average_lead_time_days {
+            Some(days) => println!("{} lead time: {:.1} days (average)", last, days),
+            None => println!("{} lead time: -", last),
+        }
+
+        if !self.tags.is_empty() {
+            println!("Tags:");
+            let num_tags = self.tags.len();
+            for (ii, (tag, count)) in self.tags.iter().enumerate() {
+                let branch = if ii == num_tags - 1 { last } else { line };
+                println!("{} [{}] {}", branch, tag.red(), count);
+            }
+        }
+
+        if !self.oldest_open.is_empty() {
+            println!("Oldest open:");
+            let num_tasks = self.oldest_open.len();
+            for (ii, task) in self.oldest_open.iter().enumerate() {
+                let branch = if ii == num_tasks - 1 { last } else { line };
+                println!(
+                    "{} {:>5.1}d {}: {}",
+                    branch,
+                    task.age_days,
+                    task.prefix.yellow().bold(),
+                    task.desc
+                );
+            }
+        }
+
+        if !self.weeks.is_empty() {
+            println!("Weekly (created / completed):");
+            let num_weeks = self.weeks.len();
+            for (ii, week) in self.weeks.iter().enumerate() {
+                let branch = if ii == num_weeks - 1 { last } else { line };
+                let label = match week.weeks_ago {
+                    0 => "this week".to_string(),
+                    1 => "1 week ago".to_string(),
+                    n => format!("{} weeks ago", n),
+                };
+                println!("{} {:<12} {:>4} / {}", branch, label, week.created, week.completed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::temp_file;
+    use crate::task_list;
+    use std::fs;
+
+    #[test]
+    fn counts_tasks_tags_and_depth() {
+        let mut tasks = task_list::create_from_file(&temp_file("stats.t"), Some("text")).unwrap();
+        tasks.set_quiet(true);
+        tasks.add_task(None, Some("top"), "Top").unwrap();
+        tasks.add_task(Some("top"), Some("mid"), "Mid").unwrap();
+        tasks.add_task(Some("mid"), Some("leaf"), "Leaf").unwrap();
+        tasks.add_task(None, Some("other"), "Other").unwrap();
+        tasks.get_task("leaf").unwrap().add_tag("urgent");
+        tasks.get_task("other").unwrap().add_tag("urgent");
+        tasks.get_task("other").unwrap().add_tag("later");
+        tasks.complete_task("leaf", false).unwrap();
+
+        let stats = compute(&tasks, 2, 2);
+        assert_eq!((stats.total, stats.open, stats.completed), (4, 3, 1));
+        assert_eq!(stats.tags.get("urgent"), Some(&2));
+        assert_eq!(stats.tags.get("later"), Some(&1));
+        assert_eq!(stats.depth, 3);
+        assert_eq!(stats.oldest_open.len(), 2);
+        assert_eq!(stats.oldest_open[0].id, "top");
+        assert_eq!((stats.weeks[0].created, stats.weeks[0].completed), (4, 1));
+        assert_eq!((stats.weeks[1].created, stats.weeks[1].completed), (0, 0));
+        assert!(stats.average_lead_time_days.is_some_and(|days| (0.0..1.0).contains(&days)));
+    }
+
+    #[test]
+    fn tasks_without_timestamps_are_left_out_of_ages() {
+        let mut tasks =
+            task_list::create_from_file(&temp_file("stats-old.t"), Some("text")).unwrap();
+        tasks.set_quiet(true);
+        tasks.upsert_task(task::create_from_file_string(r#"Old | {"id":"old"}"#).unwrap());
+
+        let stats = compute(&tasks, 1, 5);
+        assert_eq!(stats.total, 1);
+        assert!(stats.oldest_open.is_empty());
+        assert_eq!(stats.weeks[0].created, 0);
+        assert!(stats.average_lead_time_days.is_none());
+        assert!(stats.to_json().contains("\"total\": 1"));
+    }
+
+    #[test]
+    fn parent_cycles_have_a_depth() {
+        let file = temp_file("stats-cycle.t");
+        let lines = [
+            r#"A | {"id":"a","parent_id":"b","timestamp":1.0}"#,
+            r#"B | {"id":"b","parent_id":"a","timestamp":1.0}"#,
+            r#"C | {"id":"c","parent_id":"b","timestamp":1.0}"#,
+        ];
+        fs::write(&file, lines.join("\n") + "\n").unwrap();
+        let tasks = task_list::create_from_file(&file, Some("text")).unwrap();
+
+        assert_eq!(compute(&tasks, 1, 1).depth, 3);
+        fs::remove_file(file).unwrap();
+    }
+}
@@ -0,0 +1,482 @@
+use super::task;
+use super::task_list::TaskListError;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufWriter, Write};
+use std::path::Path;
+
+// Everything stored alongside the tasks themselves.
+#[derive(Clone, Default)]
+pub struct Header {
+    // Other task files shown along with this one, relative to this file
+    pub includes: Vec<String>,
+    // The file has been synced, so removals are recorded as tombstones
+    pub synced: bool,
+    // When each removed task was removed, by id, so that `sync` can tell a removed task from one
+    // that was never there
+    pub tombstones: BTreeMap<String, f64>,
+    // The number of the next task added if tasks are numbered #1, #2 and so on instead of hashed
+    pub next_id: Option<u64>,
+}
+
+// Where a task list is loaded from and saved to.
+pub trait Storage: Send {
+    fn load(&mut self) -> Result<(Header, Vec<task::Task>), TaskListError>;
+
+    fn save(&mut self, header: &Header, tasks: &[&task::Task]) -> Result<(), TaskListError>;
+
+    // Save only the tasks that changed since the last load or save.  Backends that cannot update
+    // tasks in place rewrite everything.
+    fn save_changes(
+        &mut self,
+        header: &Header,
+        tasks: &[&task::Task],
+        _changed: &[&task::Task],
+        _removed: &[String],
+    ) -> Result<(), TaskListError> {
+        self.save(header, tasks)
+    }
+}
+
+// The default backend, one task per line, after any header lines:
+//
+//   @include <path>
+//   @synced
+//   @deleted <id> <timestamp>
+//   @id-scheme sequential
+//   @next-id <number>
+//   description | {"id":"...", ...}
+pub struct TextStorage {
+    file: String,
+}
+
+// The output is wrapped in a Result to allow matching on errors
+// Returns an Iterator to the Reader of the lines of the file.
+fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>>
+where
+    P: AsRef<Path>,
+{
+    let file = File::open(filename)?;
+    Ok(io::BufReader::new(file).lines())
+}
+
+// Header lines are the directives above, any other line is a task, even one starting with '@'.
+fn parse_header_line(header: &mut Header, line: &str) -> bool {
+    if line.contains(" | ") {
+        return false;
+    }
+
+    if let Some(path) = line.strip_prefix("@include ") {
+        header.includes.push(path.trim().to_string());
+        return true;
+    }
+
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words.as_slice() {
+        ["@synced"] => {
+            header.synced = true;
+            true
+        }
+        ["@deleted", id, timestamp] => match timestamp.parse::<f64>() {
+            Ok(timestamp) => {
+                header.tombstones.insert(id.to_string(), timestamp);
+                true
+            }
+            Err(_) => false,
+        },
+        ["@id-scheme", "sequential"] => {
+            header.next_id.get_or_insert(1);
+            true
+        }
+        ["@next-id", next_id] => match next_id.parse::<u64>() {
+            Ok(next_id) => {
+                header.next_id = Some(next_id);
+                true
+            }
+            Err(_) => false,
+        },
+        _ => false,
+    }
+}
+
+impl Storage for TextStorage {
+    fn load(&mut self) -> Result<(Header, Vec<task::Task>), TaskListError> {
+        let mut header = Header::default();
+        let mut tasks = Vec::new();
+
+        if let Ok(lines) = read_lines(&self.file) {
+            for (number, task_string) in lines.map_while(Result::ok).enumerate() {
+                if parse_header_line(&mut header, &task_string) {
+                    continue;
+                }
+                match task::create_from_file_string(&task_string) {
+                    Ok(task) => tasks.push(task),
+                    Err(e) => {
+                        let message = format!("{}, line {}: {}", self.file, number + 1, e);
+                        return Err(TaskListError::StorageError(message));
+                    }
+                }
+            }
+        }
+
+        Ok((header, tasks))
+    }
+
+    fn save(&mut self, header: &Header, tasks: &[&task::Task]) -> Result<(), TaskListError> {
+        // Write to a temporary file first and then move it over the task file, so that the task
+        // file is never left half written.
+        let tmp_file = format!("{}.tmp", self.file);
+        let mut file = BufWriter::new(File::create(&tmp_file)?);
+
+        for path in &header.includes {
+            writeln!(file, "@include {}", path)?;
+        }
+        if header.synced {
+            writeln!(file, "@synced")?;
+        }
+        for (id, timestamp) in &header.tombstones {
+            writeln!(file, "@deleted {} {}", id, timestamp)?;
+        }
+        if let Some(next_id) = header.next_id {
+            writeln!(file, "@id-scheme sequential")?;
+            writeln!(file, "@next-id {}", next_id)?;
+        }
+
+        let mut sorted_tasks: Vec<&task::Task> = tasks.to_vec();
+        sorted_tasks.sort_by(|a, b| a.id().partial_cmp(b.id()).unwrap());
+
+        for task in &sorted_tasks {
+            file.write_all((task.to_file_string() + "\n").as_bytes())?;
+        }
+
+        file.flush()?;
+        drop(file);
+        fs::rename(&tmp_file, &self.file)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sqlite")]
+pub struct SqliteStorage {
+    connection: rusqlite::Connection,
+}
+
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for TaskListError {
+    fn from(error: rusqlite::Error) -> Self {
+        TaskListError::StorageError(error.to_string())
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteStorage {
+    fn open(file: &str) -> Result<SqliteStorage, TaskListError> {
+        let connection = rusqlite::Connection::open(file)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS tasks (
+                id TEXT PRIMARY KEY,
+                desc TEXT NOT NULL,
+                data TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS tombstones (
+                id TEXT PRIMARY KEY,
+                timestamp REAL NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS settings (
+                name TEXT PRIMARY KEY,
+                value TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS includes (
+                position INTEGER PRIMARY KEY,
+                path TEXT NOT NULL
+            );",
+        )?;
+        Ok(SqliteStorage { connection })
+    }
+
+    fn upsert(transaction: &rusqlite::Transaction, task: &task::Task) -> Result<(), TaskListError> {
+        transaction.execute(
+            "INSERT INTO tasks (id, desc, data) VALUES (?1, ?2, ?3)
+             ON CONFLICT(id) DO UPDATE SET desc = excluded.desc, data = excluded.data",
+            (task.id(), task.desc(), task.to_json()),
+        )?;
+        Ok(())
+    }
+
+    // Write the includes, tombstones and settings, replacing those stored before.
+    fn save_header(
+        transaction: &rusqlite::Transaction,
+        header: &Header,
+    ) -> Result<(), TaskListError> {
+        transaction.execute("DELETE FROM includes", [])?;
+        for (position, path) in header.includes.iter().enumerate() {
+            transaction.execute(
+                "INSERT INTO includes (position, path) VALUES (?1, ?2)",
+                (position, path),
+            )?;
+        }
+
+        transaction.execute("DELETE FROM tombstones", [])?;
+        for (id, timestamp) in &header.tombstones {
+            transaction.execute(
+                "INSERT INTO tombstones (id, timestamp) VALUES (?1, ?2)",
+                (id, timestamp),
+            )?;
+        }
+
+        match header.next_id {
+            Some(next_id) => transaction.execute(
+                "INSERT OR REPLACE INTO settings (name, value) VALUES ('next-id', ?1)",
+                [next_id.to_string()],
+            )?,
+            None => transaction.execute("DELETE FROM settings WHERE name = 'next-id'", [])?,
+        };
+        match header.synced {
+            true => transaction.execute(
+                "INSERT OR REPLACE INTO settings (name, value) VALUES ('synced', '1')",
+                [],
+            )?,
+            false => transaction.execute("DELETE FROM settings WHERE name = 'synced'", [])?,
+        };
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl Storage for SqliteStorage {
+    fn load(&mut self) -> Result<(Header, Vec<task::Task>), TaskListError> {
+        let mut header = Header::default();
+        let mut statement =
+            self.connection.prepare("SELECT path FROM includes ORDER BY position")?;
+        for path in statement.query_map([], |row| row.get::<_, String>(0))? {
+            header.includes.push(path?);
+        }
+
+        let mut statement = self.connection.prepare("SELECT id, timestamp FROM tombstones")?;
+        let rows =
+            statement.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, f64>(1)?)))?;
+        for row in rows {
+            let (id, timestamp) = row?;
+            header.tombstones.insert(id, timestamp);
+        }
+
+        let mut statement =
+            self.connection.prepare("SELECT value FROM settings WHERE name = 'next-id'")?;
+        let mut rows = statement.query_map([], |row| row.get::<_, String>(0))?;
+        if let Some(next_id) = rows.next() {
+            header.next_id = next_id?.parse().ok();
+        }
+
+        let mut statement =
+            self.connection.prepare("SELECT value FROM settings WHERE name = 'synced'")?;
+        header.synced = statement.exists([])?;
+
+        let mut statement = self.connection.prepare("SELECT desc, data FROM tasks")?;
+        let rows = statement
+            .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+
+        let mut tasks = Vec::new();
+        for row in rows {
+            let (desc, data) = row?;
+            match task::create_from_json(&desc, &data) {
+                Ok(task) => tasks.push(task),
+                Err(e) => return Err(TaskListError::StorageError(e.to_string())),
+            }
+        }
+
+        Ok((header, tasks))
+    }
+
+    fn save(&mut self, header: &Header, tasks: &[&task::Task]) -> Result<(), TaskListError> {
+        let transaction = self.connection.transaction()?;
+        transaction.execute("DELETE FROM tasks", [])?;
+        for task in tasks {
+            SqliteStorage::upsert(&transaction, task)?;
+        }
+        SqliteStorage::save_header(&transaction, header)?;
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    fn save_changes(
+        &mut self,
+        header: &Header,
+        _tasks: &[&task::Task],
+        changed: &[&task::Task],
+        removed: &[String],
+    ) -> Result<(), TaskListError> {
+        let transaction = self.connection.transaction()?;
+        for id in removed {
+            transaction.execute("DELETE FROM tasks WHERE id = ?1", [id])?;
+        }
+        for task in changed {
+            SqliteStorage::upsert(&transaction, task)?;
+        }
+        SqliteStorage::save_header(&transaction, header)?;
+        transaction.commit()?;
+
+        Ok(())
+    }
+}
+
+// Open the backend named `backend`, or pick one based on the file extension.
+pub fn open(file: &str, backend: Option<&str>) -> Result<Box<dyn Storage>, TaskListError> {
+    let backend = match backend {
+        Some(backend) => backend,
+        None => match Path::new(file).extension().and_then(|ext| ext.to_str()) {
+            Some("db") | Some("sqlite") | Some("sqlite3") => "sqlite",
+            _ => "text",
+        },
+    };
+
+    match backend {
+        "text" => Ok(Box::new(TextStorage { file: file.to_string() })),
+        #[cfg(feature = "sqlite")]
+        "sqlite" => Ok(Box::new(SqliteStorage::open(file)?)),
+        backend => Err(TaskListError::UnsupportedBackend(backend.to_string())),
+    }
+}
+
+// A path in the temporary directory that no other test uses, with no file at it yet.
+#[cfg(test)]
+pub fn temp_file(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!("t-test-{}-{}", std::process::id(), name));
+    let _ = fs::remove_file(&path);
+    path.to_string_lossy().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> Header {
+        let mut header = Header {
+            includes: vec!["other.t".to_string()],
+            synced: true,
+            next_id: Some(7),
+            ..Header::default()
+        };
+        header.tombstones.insert("dead".to_string(), 1700000000.5);
+        header
+    }
+
+    fn tasks() -> Vec<task::Task> {
+        let mut sub_task = task::create(Some("top"), None, "Sub-task | with a bar");
+        sub_task.add_tag("urgent");
+        vec![task::create(None, Some("top"), "Top"), sub_task]
+    }
+
+    fn round_trip(storage: &mut dyn Storage) {
+        let (header, tasks) = (header(), tasks());
+        storage.save(&header, &tasks.iter().collect::<Vec<_>>()).unwrap();
+
+        let (loaded_header, loaded) = storage.load().unwrap();
+        assert_eq!(loaded_header.includes, ["other.t"]);
+        assert_eq!(loaded_header.tombstones, header.tombstones);
+        assert_eq!(loaded_header.next_id, Some(7));
+        assert!(loaded_header.synced);
+
+        let mut ids: Vec<&str> = loaded.iter().map(|task| task.id().as_str()).collect();
+        let mut expected: Vec<&str> = tasks.iter().map(|task| task.id().as_str()).collect();
+        ids.sort();
+        expected.sort();
+        assert_eq!(ids, expected);
+        let sub_task = loaded.iter().find(|task| task.id() == tasks[1].id()).unwrap();
+        assert_eq!(sub_task.desc(), "Sub-task | with a bar");
+        assert_eq!(sub_task.parent_id().as_deref(), Some("top"));
+        assert_eq!(sub_task.tags(), &["urgent"]);
+    }
+
+    #[test]
+    fn text_round_trip() {
+        let file = temp_file("round-trip.t");
+        let mut storage = open(&file, Some("text")).unwrap();
+        round_trip(storage.as_mut());
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn missing_text_file_is_empty() {
+        let mut storage = open(&temp_file("missing.t"), None).unwrap();
+        let (header, tasks) = storage.load().unwrap();
+        assert!(header.includes.is_empty() && !header.synced && header.next_id.is_none());
+        assert!(tasks.is_empty());
+    }
+
+    #[test]
+    fn unsupported_backend() {
+        assert!(matches!(
+            open("tasks.t", Some("csv")),
+            Err(TaskListError::UnsupportedBackend(backend)) if backend == "csv"
+        ));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_round_trip() {
+        let file = temp_file("round-trip.db");
+        let mut storage = open(&file, None).unwrap();
+        round_trip(storage.as_mut());
+        fs::remove_file(file).unwrap();
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_save_changes() {
+        let file = temp_file("changes.db");
+        let mut storage = open(&file, None).unwrap();
+        let tasks = tasks();
+        storage.save(&header(), &tasks.iter().collect::<Vec<_>>()).unwrap();
+
+        let mut changed = tasks[0].clone();
+        changed.set_desc("Top, renamed");
+        let header = Header::default();
+        storage.save_changes(&header, &[], &[&changed], &[tasks[1].id().to_string()]).unwrap();
+
+        let (header, loaded) = storage.load().unwrap();
+        assert!(header.includes.is_empty() && header.tombstones.is_empty());
+        assert!(!header.synced && header.next_id.is_none());
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].desc(), "Top, renamed");
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn only_known_directives_are_header_lines() {
+        let mut header = Header::default();
+        for line in ["@include a.t", "@synced", "@deleted abc 12.5", "@id-scheme sequential"] {
+            assert!(parse_header_line(&mut header, line), "{}", line);
+        }
+        assert!(parse_header_line(&mut header, "@next-id 3"));
+        assert_eq!(header.includes, ["a.t"]);
+        assert!(header.synced);
+        assert_eq!(header.tombstones.get("abc"), Some(&12.5));
+        assert_eq!(header.next_id, Some(3));
+
+        for line in [
+            "@home call mom",
+            "@synced twice",
+            "@deleted abc",
+            "@deleted abc yesterday",
+            "@id-scheme hash",
+            "@next-id soon",
+            "@include a.t | {\"id\":\"abc\"}",
+            "Buy milk",
+        ] {
+            assert!(!parse_header_line(&mut header, line), "{}", line);
+        }
+    }
+
+    #[test]
+    fn unreadable_lines_are_an_error() {
+        let file = temp_file("unreadable.t");
+        fs::write(&file, "Task | {\"id\":\"abc\"}\ngrep foo | wc\n").unwrap();
+        let error = open(&file, None).unwrap().load().err().unwrap();
+        assert!(
+            matches!(error, TaskListError::StorageError(message) if message.contains("line 2"))
+        );
+        fs::remove_file(file).unwrap();
+    }
+}
@@ -0,0 +1,310 @@
+use super::task;
+use super::task_list::{TaskList, TaskListError};
+use colored::Colorize;
+use std::collections::{BTreeMap, BTreeSet};
+
+// What a task should look like on both sides once synced.
+enum Outcome {
+    Keep(Box<task::Task>),
+    Removed(f64),
+}
+
+// Changes made to one side, as (marker, prefix, description) lines.
+struct Changes {
+    lines: Vec<(char, String, String)>,
+}
+
+// Combine two versions of a task field by field, the most recently modified value wins.  Ties go
+// to `local`.
+fn sync_task(local: &task::Task, remote: &task::Task) -> task::Task {
+    let (lm, rm) = (local.modified(), remote.modified());
+
+    let mut task = local.clone();
+    if rm.desc > lm.desc {
+        task.set_desc(remote.desc());
+    }
+    if rm.parent > lm.parent {
+        task.set_parent_id(remote.parent_id().as_deref());
+    }
+    if rm.tags > lm.tags {
+        task.set_tags(remote.tags().clone());
+    }
+    if rm.completed > lm.completed {
+        task.set_completed_timestamp(remote.completed_timestamp());
+    }
+    if rm.due > lm.due {
+        task.set_due(remote.due());
+    }
+    if rm.priority > lm.priority {
+        task.set_priority(remote.priority());
+    }
+    task.set_modified(lm.max(rm));
+
+    task
+}
+
+fn outcome(
+    local: Option<&task::Task>,
+    remote: Option<&task::Task>,
+    removed: Option<f64>,
+) -> Outcome {
+    let task = match (local, remote) {
+        (Some(local), Some(remote)) => sync_task(local, remote),
+        (Some(task), None) | (None, Some(task)) => task.clone(),
+        (None, None) => return Outcome::Removed(removed.unwrap_or(0.0)),
+    };
+
+    // A removal only wins over changes made before it
+    match removed {
+        Some(removed) if removed >= task.last_modified() => Outcome::Removed(removed),
+        _ => Outcome::Keep(Box::new(task)),
+    }
+}
+
+fn apply(tasks: &mut TaskList, outcomes: &BTreeMap<String, Outcome>) -> Changes {
+    let mut changes = Changes { lines: Vec::new() };
+    let tombstones = tasks.tombstones();
+
+    for (id, outcome) in outcomes {
+        let existing = tasks.task_by_id(id).cloned();
+        match (outcome, existing) {
+            (Outcome::Keep(task), Some(existing)) if **task == existing => {}
+            (Outcome::Keep(task), existing) => {
+                let marker = if existing.is_some() { '~' } else { '+' };
+                let desc = task.desc().to_string();
+                tasks.upsert_task((**task).clone());
+                changes.lines.push((marker, prefix_of(tasks, id), desc));
+            }
+            (Outcome::Removed(timestamp), Some(existing)) => {
+                let prefix = prefix_of(tasks, id);
+                tasks.delete_task(id, *timestamp);
+                changes.lines.push(('-', prefix, existing.desc().to_string()));
+            }
+            (Outcome::Removed(timestamp), None) => {
+                if tombstones.get(id) != Some(timestamp) {
+                    tasks.delete_task(id, *timestamp);
+                }
+            }
+        }
+    }
+
+    changes
+}
+
+fn prefix_of(tasks: &TaskList, id: &str) -> String {
+    tasks.prefix(id).map_or(id, |prefix| prefix.as_str()).to_string()
+}
+
+fn show_changes(file: &str, changes: &Changes) {
+    if changes.lines.is_empty() {
+        println!("{}: up to date", file);
+        return;
+    }
+
+    let count = |marker| changes.lines.iter().filter(|(m, _, _)| *m == marker).count();
+    println!("{}: {} added, {} updated, {} removed", file, count('+'), count('~'), count('-'));
+    for (marker, prefix, desc) in &changes.lines {
+        let marker = match marker {
+            '+' => "+".green(),
+            '-' => "-".red(),
+            _ => "~".yellow(),
+        };
+        println!("  {} {}: {}", marker, prefix.yellow().bold(), desc);
+    }
+}
+
+// Bring `local` and `remote` up to date with each other and save both.  Tasks are matched by id,
+// edits are resolved field by field using their modification times and removals are carried over
+// using each side's tombstones.
+pub fn sync(local: &mut TaskList, remote: &mut TaskList) -> Result<(), TaskListError> {
+    local.set_synced();
+    remote.set_synced();
+
+    let (local_tombstones, remote_tombstones) = (local.tombstones(), remote.tombstones());
+    let ids: BTreeSet<String> = local
+        .tasks()
+        .iter()
+        .chain(remote.tasks().iter())
+        .map(|task| task.id().to_string())
+        .chain(local_tombstones.keys().cloned())
+        .chain(remote_tombstones.keys().cloned())
+        .collect();
+
+    let mut outcomes = BTreeMap::new();
+    for id in ids {
+        let removed = match (local_tombstones.get(&id), remote_tombstones.get(&id)) {
+            (Some(l), Some(r)) => Some(l.max(*r)),
+            (l, r) => l.or(r).cloned(),
+        };
+        let outcome = outcome(local.task_by_id(&id), remote.task_by_id(&id), removed);
+        outcomes.insert(id, outcome);
+    }
+
+    // Sub-tasks that outlived their parent become top level tasks...
+    let kept: BTreeSet<String> = outcomes
+        .iter()
+        .filter(|(_, outcome)| matches!(outcome, Outcome::Keep(_)))
+        .map(|(id, _)| id.to_string())
+        .collect();
+    for outcome in outcomes.values_mut() {
+        if let Outcome::Keep(task) = outcome {
+            if task.parent_id().as_ref().is_some_and(|parent_id| !kept.contains(parent_id)) {
+                task.set_parent_id(None);
+            }
+        }
+    }
+    // and so does a task moved under its own sub-task on the other side
+    let mut kept: Vec<&mut task::Task> = outcomes
+        .values_mut()
+        .filter_map(|outcome| match outcome {
+            Outcome::Keep(task) => Some(&mut **task),
+            Outcome::Removed(_) => None,
+        })
+        .collect();
+    task::break_parent_cycles(&mut kept);
+
+    let local_changes = apply(local, &outcomes);
+    let remote_changes = apply(remote, &outcomes);
+
+    local.save()?;
+    remote.save()?;
+
+    if !local.quiet() {
+        show_changes(local.file(), &local_changes);
+        show_changes(remote.file(), &remote_changes);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::temp_file;
+    use crate::task_list;
+    use std::fs;
+
+    fn task(desc: &str, modified: task::Modified) -> task::Task {
+        let mut task =
+            task::create_from_file_string(&format!(r#"{} | {{"id":"a","timestamp":1.0}}"#, desc))
+                .unwrap();
+        task.set_modified(modified);
+        task
+    }
+
+    fn task_list(file: &str) -> TaskList {
+        let mut tasks = task_list::create_from_file(file, Some("text")).unwrap();
+        tasks.set_quiet(true);
+        tasks
+    }
+
+    fn ids(tasks: &TaskList) -> Vec<&str> {
+        let mut ids: Vec<&str> = tasks.tasks().iter().map(|task| task.id().as_str()).collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn later_changes_win_field_by_field() {
+        let local = task("Local", task::Modified { desc: 20.0, tags: 10.0, ..Default::default() });
+        let mut remote = task("Remote", task::Modified::default());
+        remote.add_tag("urgent");
+        remote.set_modified(task::Modified { desc: 15.0, tags: 30.0, ..Default::default() });
+
+        let task = sync_task(&local, &remote);
+        assert_eq!(task.desc(), "Local");
+        assert_eq!(task.tags(), &["urgent"]);
+        assert!(
+            *task.modified() == task::Modified { desc: 20.0, tags: 30.0, ..Default::default() }
+        );
+    }
+
+    #[test]
+    fn ties_go_to_local() {
+        let modified = task::Modified { desc: 10.0, ..Default::default() };
+        assert_eq!(sync_task(&task("Local", modified), &task("Remote", modified)).desc(), "Local");
+    }
+
+    #[test]
+    fn removals_only_win_over_earlier_changes() {
+        let task = task("Task", task::Modified { desc: 10.0, ..Default::default() });
+        assert!(matches!(outcome(Some(&task), None, Some(20.0)), Outcome::Removed(20.0)));
+        assert!(matches!(outcome(Some(&task), None, Some(5.0)), Outcome::Keep(_)));
+        assert!(matches!(outcome(None, None, Some(5.0)), Outcome::Removed(5.0)));
+    }
+
+    #[test]
+    fn sync_adds_and_removes_on_both_sides() {
+        let (local_file, remote_file) = (temp_file("sync-local.t"), temp_file("sync-remote.t"));
+        let mut local = task_list(&local_file);
+        let mut remote = task_list(&remote_file);
+        local.add_task(None, Some("a"), "A").unwrap();
+        local.add_task(Some("a"), Some("c"), "Sub-task").unwrap();
+        remote.add_task(None, Some("b"), "B").unwrap();
+        local.save().unwrap();
+        remote.save().unwrap();
+
+        sync(&mut local, &mut remote).unwrap();
+        assert_eq!(ids(&local), ["a", "b", "c"]);
+        assert_eq!(ids(&remote), ["a", "b", "c"]);
+
+        // Removals are carried over by tombstones once both sides are synced
+        let mut remote = task_list(&remote_file);
+        assert!(remote.synced());
+        remote.remove_task("a", true).unwrap();
+        remote.save().unwrap();
+        let mut local = task_list(&local_file);
+        local.get_task("b").unwrap().set_desc("B, changed");
+        local.save().unwrap();
+
+        sync(&mut local, &mut remote).unwrap();
+        let (local, remote) = (task_list(&local_file), task_list(&remote_file));
+        assert_eq!(ids(&local), ["b"]);
+        assert_eq!(ids(&remote), ["b"]);
+        assert_eq!(remote.task_by_id("b").unwrap().desc(), "B, changed");
+        fs::remove_file(local_file).unwrap();
+        fs::remove_file(remote_file).unwrap();
+    }
+
+    #[test]
+    fn sub_tasks_of_removed_tasks_become_top_level() {
+        let (local_file, remote_file) = (temp_file("orphan-local.t"), temp_file("orphan-remote.t"));
+        let mut local = task_list(&local_file);
+        let mut remote = task_list(&remote_file);
+        local.add_task(None, Some("a"), "A").unwrap();
+        sync(&mut local, &mut remote).unwrap();
+
+        // Added under "a" on one side while "a" was removed on the other
+        local.add_task(Some("a"), Some("c"), "Sub-task").unwrap();
+        remote.remove_task("a", false).unwrap();
+        sync(&mut local, &mut remote).unwrap();
+
+        for tasks in [&local, &remote] {
+            assert_eq!(ids(tasks), ["c"]);
+            assert_eq!(*tasks.task_by_id("c").unwrap().parent_id(), None);
+        }
+        fs::remove_file(local_file).unwrap();
+        fs::remove_file(remote_file).unwrap();
+    }
+
+    #[test]
+    fn moves_that_form_a_cycle_are_undone() {
+        let (local_file, remote_file) = (temp_file("cycle-local.t"), temp_file("cycle-remote.t"));
+        let mut local = task_list(&local_file);
+        let mut remote = task_list(&remote_file);
+        local.add_task(None, Some("a"), "A").unwrap();
+        local.add_task(None, Some("b"), "B").unwrap();
+        sync(&mut local, &mut remote).unwrap();
+
+        local.move_task("a", Some("b")).unwrap();
+        remote.move_task("b", Some("a")).unwrap();
+        sync(&mut local, &mut remote).unwrap();
+
+        for tasks in [&local, &remote] {
+            assert_eq!(*tasks.task_by_id("a").unwrap().parent_id(), None);
+            assert_eq!(tasks.task_by_id("b").unwrap().parent_id().as_deref(), Some("a"));
+        }
+        fs::remove_file(local_file).unwrap();
+        fs::remove_file(remote_file).unwrap();
+    }
+}
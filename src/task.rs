@@ -1,12 +1,145 @@
 use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
 use std::time::SystemTime;
 
 fn is_false(operand: &bool) -> bool {
     !operand
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+fn is_zero(operand: &f64) -> bool {
+    *operand == 0.0
+}
+
+pub fn now() -> f64 {
+    match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        Err(_) => 0.0,
+        Ok(ts) => ts.as_secs_f64(),
+    }
+}
+
+// Format a timestamp as a UTC date and time, e.g. '2024-03-01 14:05 UTC'.
+pub fn format_timestamp(timestamp: f64) -> String {
+    let seconds = timestamp as i64;
+    let (days, seconds) = (seconds.div_euclid(86400), seconds.rem_euclid(86400));
+
+    // Civil date from days since 1970-01-01, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60
+    )
+}
+
+// The date in `text` as YYYY-MM-DD, None if it is not a valid date.
+pub fn parse_date(text: &str) -> Option<String> {
+    let parts: Vec<&str> = text.split('-').collect();
+    let [year, month, day] = parts.as_slice() else {
+        return None;
+    };
+    if year.len() != 4 || !parts.iter().all(|part| part.chars().all(|c| c.is_ascii_digit())) {
+        return None;
+    }
+    let (year, month, day): (u32, u32, u32) =
+        (year.parse().ok()?, month.parse().ok()?, day.parse().ok()?);
+
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        1..=12 => 31,
+        _ => return None,
+    };
+    if day == 0 || day > days {
+        return None;
+    }
+
+    Some(format!("{:04}-{:02}-{:02}", year, month, day))
+}
+
+// How urgent a task is, ordered most urgent first.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    High,
+    Medium,
+    Low,
+}
+
+impl Priority {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Priority::High => "high",
+            Priority::Medium => "medium",
+            Priority::Low => "low",
+        }
+    }
+}
+
+impl FromStr for Priority {
+    type Err = ();
+
+    fn from_str(text: &str) -> Result<Priority, ()> {
+        match text {
+            "high" => Ok(Priority::High),
+            "medium" => Ok(Priority::Medium),
+            "low" => Ok(Priority::Low),
+            _ => Err(()),
+        }
+    }
+}
+
+// When each editable field of a task was last changed, 0 if never.
+#[derive(Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Modified {
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub desc: f64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub parent: f64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub tags: f64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub completed: f64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub due: f64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub priority: f64,
+}
+
+impl Modified {
+    fn is_unset(&self) -> bool {
+        *self == Modified::default()
+    }
+
+    // The later of each field's modification times.
+    pub fn max(&self, other: &Modified) -> Modified {
+        Modified {
+            desc: self.desc.max(other.desc),
+            parent: self.parent.max(other.parent),
+            tags: self.tags.max(other.tags),
+            completed: self.completed.max(other.completed),
+            due: self.due.max(other.due),
+            priority: self.priority.max(other.priority),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct Task {
     id: String,
     #[serde(default, skip_serializing)]
@@ -19,8 +152,21 @@ pub struct Task {
     timestamp: f64,
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     tags: Vec<String>,
+    // As YYYY-MM-DD
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    due: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    priority: Option<Priority>,
     #[serde(default)]
     completed_timestamp: f64,
+    #[serde(default, skip_serializing_if = "Modified::is_unset")]
+    modified: Modified,
+    // Sub-tasks are not shown in the tree
+    #[serde(default, skip_serializing_if = "is_false")]
+    collapsed: bool,
+    // Stable prefixes the task was shown with, the last one is current
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    prefixes: Vec<String>,
 }
 
 impl Task {
@@ -32,95 +178,205 @@ impl Task {
         &self.desc
     }
 
+    // The task as a line of a task file, with '|' and '\' in the description escaped.
     pub fn to_file_string(&self) -> String {
-        let json = serde_json::to_string(&self).unwrap();
-        format!("{} | {}", self.desc(), json)
+        let desc = self.desc.replace('\\', "\\\\").replace('|', "\\|");
+        format!("{} | {}", desc, self.to_json())
+    }
+
+    // Everything but the description, which is stored separately.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self).unwrap()
+    }
+
+    // Everything, the description included, as given to hooks and `t serve` clients.
+    pub fn to_json_value(&self) -> serde_json::Value {
+        let mut json = serde_json::to_value(self).unwrap();
+        json["desc"] = self.desc.as_str().into();
+        json
     }
 
     pub fn show_full_id(&self) -> bool {
         self.show_full_id
     }
 
+    // Replace the id with a custom one, which like other custom ids is shown in full.
+    pub fn set_id(&mut self, id: &str) {
+        self.id = id.to_string();
+        self.show_full_id = true;
+        // Stable prefixes of the old id don't find the new one
+        self.prefixes.clear();
+    }
+
     pub fn timestamp(&self) -> f64 {
         self.timestamp
     }
 
     pub fn set_desc(&mut self, desc: &str) {
-        self.desc = desc.to_string();
+        if self.desc != desc {
+            self.desc = desc.to_string();
+            self.modified.desc = now();
+        }
     }
 
     pub fn parent_id(&self) -> &Option<String> {
         &self.parent_id
     }
 
+    pub fn set_parent_id(&mut self, parent_id: Option<&str>) {
+        if self.parent_id.as_deref() != parent_id {
+            self.parent_id = parent_id.map(String::from);
+            self.modified.parent = now();
+        }
+    }
+
     pub fn add_tag(&mut self, tag: &str) {
         if !self.tags.contains(&tag.to_string()) {
             self.tags.push(tag.to_string());
+            self.modified.tags = now();
         }
     }
 
     pub fn remove_tag(&mut self, tag: &str) {
-        self.tags.retain(|x| x != tag);
+        if self.tags.iter().any(|x| x == tag) {
+            self.tags.retain(|x| x != tag);
+            self.modified.tags = now();
+        }
     }
 
     pub fn tags(&self) -> &Vec<String> {
         &self.tags
     }
 
+    pub fn set_tags(&mut self, tags: Vec<String>) {
+        if self.tags != tags {
+            self.tags = tags;
+            self.modified.tags = now();
+        }
+    }
+
+    pub fn due(&self) -> Option<&str> {
+        self.due.as_deref()
+    }
+
+    pub fn set_due(&mut self, due: Option<&str>) {
+        if self.due.as_deref() != due {
+            self.due = due.map(String::from);
+            self.modified.due = now();
+        }
+    }
+
+    pub fn priority(&self) -> Option<Priority> {
+        self.priority
+    }
+
+    pub fn set_priority(&mut self, priority: Option<Priority>) {
+        if self.priority != priority {
+            self.priority = priority;
+            self.modified.priority = now();
+        }
+    }
+
+    pub fn completed_timestamp(&self) -> f64 {
+        self.completed_timestamp
+    }
+
+    pub fn set_completed_timestamp(&mut self, completed_timestamp: f64) {
+        if self.completed_timestamp != completed_timestamp {
+            self.completed_timestamp = completed_timestamp;
+            self.modified.completed = now();
+        }
+    }
+
     pub fn is_completed(&self) -> bool {
         self.completed_timestamp != 0.0
     }
 
     pub fn set_complete(&mut self, complete: bool) {
-        if complete {
-            self.completed_timestamp =
-                match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
-                    Err(_) => 0.0,
-                    Ok(ts) => ts.as_secs_f64(),
-                };
-        } else {
-            self.completed_timestamp = 0.0;
-        }
+        let now = now();
+        self.completed_timestamp = if complete { now } else { 0.0 };
+        self.modified.completed = now;
     }
-}
 
-fn split_once(in_string: &str) -> (Option<&str>, Option<&str>) {
-    let mut splitter = in_string.splitn(2, '|');
-    let first = splitter.next();
-    let second = splitter.next();
-    (first, second)
-}
+    pub fn is_collapsed(&self) -> bool {
+        self.collapsed
+    }
 
-pub fn create_from_file_string(string: &str) -> Task {
-    let (desc, json) = split_once(string);
+    pub fn set_collapsed(&mut self, collapsed: bool) {
+        self.collapsed = collapsed;
+    }
 
-    if let Some(desc) = desc {
-        //println!("desc: {}", desc);
-        if let Some(json) = json {
-            //println!("json: {}", json);
+    pub fn prefixes(&self) -> &[String] {
+        &self.prefixes
+    }
 
-            if json.trim().is_empty() {
-                return create(None, None, desc);
-            }
+    pub fn add_prefix(&mut self, prefix: &str) {
+        self.prefixes.push(prefix.to_string());
+    }
+
+    pub fn modified(&self) -> &Modified {
+        &self.modified
+    }
+
+    pub fn set_modified(&mut self, modified: Modified) {
+        self.modified = modified;
+    }
 
-            let mut task: Task = serde_json::from_str(json).unwrap();
-            task.desc = desc.trim().to_string();
-            return task;
-        } else {
-            return create(None, None, desc);
+    // When the task was last changed, or created if it never was.
+    pub fn last_modified(&self) -> f64 {
+        let modified = &self.modified;
+        [
+            self.timestamp,
+            modified.desc,
+            modified.parent,
+            modified.tags,
+            modified.completed,
+            modified.due,
+            modified.priority,
+        ]
+        .iter()
+        .cloned()
+        .fold(0.0, f64::max)
+    }
+}
+
+// Split a task file line at the first '|' that is not escaped, unescaping the description.
+fn split_desc(line: &str) -> (String, Option<&str>) {
+    let mut desc = String::new();
+    let mut chars = line.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => match chars.peek() {
+                Some(&(_, next)) if next == '\\' || next == '|' => {
+                    desc.push(next);
+                    chars.next();
+                }
+                _ => desc.push(c),
+            },
+            '|' => return (desc, Some(&line[i + 1..])),
+            _ => desc.push(c),
         }
     }
+    (desc, None)
+}
 
-    // TODO: this should return None or an error?
-    create(None, None, "")
+// A task from a line of a task file.  A line without the JSON part is a new task.
+pub fn create_from_file_string(string: &str) -> Result<Task, serde_json::Error> {
+    match split_desc(string) {
+        (desc, Some(json)) if !json.trim().is_empty() => create_from_json(desc.trim(), json),
+        (desc, _) => Ok(create(None, None, &desc)),
+    }
+}
+
+pub fn create_from_json(desc: &str, json: &str) -> Result<Task, serde_json::Error> {
+    let mut task: Task = serde_json::from_str(json)?;
+    task.desc = desc.to_string();
+    Ok(task)
 }
 
 pub fn create(parent_id: Option<&str>, id: Option<&str>, desc: &str) -> Task {
     let show_full_id: bool;
-    let timestamp: f64 = match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
-        Err(_) => 0.0,
-        Ok(ts) => ts.as_secs_f64(),
-    };
+    let timestamp: f64 = now();
 
     let id = match id {
         None => {
@@ -142,8 +398,97 @@ pub fn create(parent_id: Option<&str>, id: Option<&str>, desc: &str) -> Task {
         desc: desc.to_string(),
         parent_id: parent_id.map(String::from),
         show_full_id,
-        timestamp: 0.0,
+        timestamp,
         tags: Vec::new(),
+        due: None,
+        priority: None,
         completed_timestamp: 0.0,
+        modified: Modified::default(),
+        collapsed: false,
+        prefixes: Vec::new(),
+    }
+}
+
+// Move tasks to the top level until none of them is its own ancestor.  Moves that are fine on
+// their own can form a cycle when two versions of a task list are combined.
+pub fn break_parent_cycles(tasks: &mut [&mut Task]) {
+    let index: HashMap<String, usize> =
+        tasks.iter().enumerate().map(|(i, task)| (task.id.clone(), i)).collect();
+
+    // Tasks whose ancestors are known not to form a cycle
+    let mut done = HashSet::new();
+    for start in 0..tasks.len() {
+        let mut path = HashSet::new();
+        let mut current = Some(start);
+        while let Some(i) = current {
+            if done.contains(&i) {
+                break;
+            }
+            if !path.insert(i) {
+                tasks[i].set_parent_id(None);
+                break;
+            }
+            current = tasks[i].parent_id.as_ref().and_then(|id| index.get(id)).copied();
+        }
+        done.extend(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_date_accepts_valid_dates() {
+        assert_eq!(parse_date("2024-01-31").as_deref(), Some("2024-01-31"));
+        assert_eq!(parse_date("2024-4-5").as_deref(), Some("2024-04-05"));
+        assert_eq!(parse_date("2024-02-29").as_deref(), Some("2024-02-29"));
+        assert_eq!(parse_date("2000-02-29").as_deref(), Some("2000-02-29"));
+    }
+
+    #[test]
+    fn parse_date_rejects_invalid_dates() {
+        for text in ["", "2024", "2024-01", "24-01-01", "2024-13-01", "2024-00-10", "2024-04-31"] {
+            assert_eq!(parse_date(text), None, "{}", text);
+        }
+        assert_eq!(parse_date("2023-02-29"), None);
+        assert_eq!(parse_date("1900-02-29"), None);
+        assert_eq!(parse_date("2024-01-+1"), None);
+        assert_eq!(parse_date("2024-01-01-01"), None);
+    }
+
+    #[test]
+    fn file_string_round_trip() {
+        let mut task = create(Some("parent"), Some("custom"), "grep foo | wc \\| \\\\ C:\\dir\\");
+        task.add_tag("urgent");
+        task.set_due(Some("2024-05-01"));
+        task.set_priority(Some(Priority::High));
+        task.set_complete(true);
+
+        let loaded = create_from_file_string(&task.to_file_string()).unwrap();
+        assert_eq!(loaded.id(), "custom");
+        assert_eq!(loaded.desc(), "grep foo | wc \\| \\\\ C:\\dir\\");
+        assert_eq!(loaded.parent_id().as_deref(), Some("parent"));
+        assert_eq!(loaded.tags(), &["urgent"]);
+        assert_eq!(loaded.due(), Some("2024-05-01"));
+        assert!(loaded.priority() == Some(Priority::High));
+        assert!(loaded.is_completed());
+        assert!(loaded.show_full_id());
+    }
+
+    #[test]
+    fn line_without_json_is_a_new_task() {
+        for line in ["Buy milk", "Buy milk |", "Buy milk |  "] {
+            let task = create_from_file_string(line).unwrap();
+            assert_eq!(task.desc().trim(), "Buy milk");
+            assert!(!task.show_full_id());
+            assert_eq!(task.id().len(), 40);
+        }
+    }
+
+    #[test]
+    fn bad_json_is_an_error() {
+        assert!(create_from_file_string("grep foo | wc").is_err());
+        assert!(create_from_file_string("Task | {\"id\":").is_err());
     }
 }
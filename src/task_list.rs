@@ -1,16 +1,44 @@
+use super::hooks::Hooks;
+use super::prefix_index::{Lookup, PrefixIndex};
+use super::storage::{self, Header, Storage};
 use super::task;
-use colored::Colorize;
-use std::collections::HashMap;
-use std::fmt::Write as _;
-use std::fs::File;
-use std::io::{self, BufRead, BufWriter, Write};
-use std::path::Path;
+use super::template::{self, Field, Template};
+use colored::{Color, Colorize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use thiserror::Error;
 
+// Tombstones older than this many days are dropped, copies synced less often may bring back
+// removed tasks
+const TOMBSTONE_DAYS: f64 = 90.0;
+
+// Ambiguous prefix errors list at most this many of the matching tasks
+const MAX_CANDIDATES: usize = 10;
+
+// Wrapped descriptions get at least this many columns, however deep the task is
+const MIN_WRAP_WIDTH: usize = 20;
+
+// Tree drawing characters and what --ascii draws instead
+const ASCII_GUIDES: &[(&str, &str)] = &[("├─", "+-"), ("└─", "`-"), ("│", "|"), ("└", "`")];
+
+// The dimmed summary after a row whose sub-tasks are left out.
+fn folded_summary(row: &TreeRow) -> Option<String> {
+    row.folded
+        .map(|(hidden, open)| format!("(+{} hidden, {} open)", hidden, open).dimmed().to_string())
+}
+
+// Replace the tree drawing characters in `text` with plain ASCII.
+pub fn ascii_guides(text: &str) -> String {
+    ASCII_GUIDES.iter().fold(text.to_string(), |text, (from, to)| text.replace(from, to))
+}
+
 #[derive(Error, Debug)]
 pub enum TaskListError {
-    #[error("Prefix matches more than one task.")]
-    AmbiguousPrefix,
+    // The prefix and "prefix: description" of the tasks it matches
+    #[error("Prefix '{0}' matches more than one task:{}", .1.iter().map(|c| format!("\n  {}", c)).collect::<String>())]
+    AmbiguousPrefix(String, Vec<String>),
 
     #[error("Prefix matches no tasks.")]
     BadPrefix,
@@ -18,61 +46,514 @@ pub enum TaskListError {
     #[error("Parent prefix matches no tasks.")]
     BadParentPrefix,
 
+    #[error("A task cannot be moved under itself or one of its sub-tasks.")]
+    MoveIntoDescendant,
+
     #[error("A task with this id already exits.")]
     DuplicateTask,
 
+    #[error("Task {0} is in more than one task file.")]
+    DuplicateTaskInFiles(String),
+
     #[error("The task you are trying to remove has children.  Use --force.")]
     RemoveHasChildren,
 
     #[error("The task you are trying to complete has uncompleted children.  Use --force.")]
     CompleteHasChildren,
 
+    #[error("Unknown filter term '{0}'.")]
+    BadFilter(String),
+
+    #[error("No tags given.")]
+    MissingTags,
+
+    #[error("Invalid due date '{0}', expected YYYY-MM-DD.")]
+    BadDate(String),
+
+    #[error("'{0}' can't be run inside the shell.")]
+    NestedShell(String),
+
+    #[error("Storage backend '{0}' is not available in this build.")]
+    UnsupportedBackend(String),
+
+    #[error("Storage error: {0}")]
+    StorageError(String),
+
+    #[error("Invalid configuration: {0}")]
+    BadConfig(String),
+
+    #[error(
+        "Task id '{0}' may only contain letters, digits, '_', '.' and '-', and not start with '-'."
+    )]
+    BadId(String),
+
+    // The new id and the existing id it collides with
+    #[error("Task id '{0}' and the id of task '{1}' would be prefixes of one another.")]
+    IdCollision(String, String),
+
+    #[error("Template name '{0}' may only contain letters, digits, '_', '.' and '-', and not start with '.' or '-'.")]
+    BadTemplateName(String),
+
+    #[error("There is no template named '{0}'.")]
+    NoSuchTemplate(String),
+
+    // The hook and why it failed
+    #[error("Hook '{0}' failed: {1}")]
+    HookFailed(String, String),
+
     // Represents all other cases of `std::io::Error`.
     #[error(transparent)]
     IOError(#[from] std::io::Error),
 }
 
-pub struct TaskList {
+// The EXIT STATUS section of the help, in numeric order.  Every code returned by
+// `TaskListError::exit_code` is listed here.
+pub const EXIT_STATUS: &str = "EXIT STATUS:
+    0   Success
+    1   Invalid command line arguments
+    3   Prefix matches more than one task, the matching tasks are listed
+    4   Prefix matches no tasks
+    5   Parent prefix matches no tasks
+    6   Task cannot be moved under itself or one of its sub-tasks
+    7   A task with this id already exists
+    8   Task is in more than one task file
+    9   Task to remove has children, use --force
+    10  Task to complete has uncompleted children, use --force
+    11  Unknown filter term
+    12  The command can't be run inside t shell
+    13  Storage backend not available in this build
+    14  Storage error
+    15  I/O error
+    16  Invalid --template or .t/config
+    17  Invalid task id
+    18  Task id would be a prefix of another task's id, or the other way around
+    19  Invalid template name
+    20  No template with that name
+    21  A hook in .t/hooks next to FILE failed, nothing was saved
+    22  Invalid due date
+    23  No tags given to tag
+
+merge-driver exits with 1 when there are conflicts and 2 on any error.";
+
+impl TaskListError {
+    // The exit status of `t` when a command fails with this error, see EXIT_STATUS.  Usage errors
+    // reported by clap exit with 1.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            TaskListError::AmbiguousPrefix(..) => 3,
+            TaskListError::BadPrefix => 4,
+            TaskListError::BadParentPrefix => 5,
+            TaskListError::MoveIntoDescendant => 6,
+            TaskListError::DuplicateTask => 7,
+            TaskListError::DuplicateTaskInFiles(_) => 8,
+            TaskListError::RemoveHasChildren => 9,
+            TaskListError::CompleteHasChildren => 10,
+            TaskListError::BadFilter(_) => 11,
+            TaskListError::NestedShell(_) => 12,
+            TaskListError::UnsupportedBackend(_) => 13,
+            TaskListError::StorageError(_) => 14,
+            TaskListError::IOError(_) => 15,
+            TaskListError::BadConfig(_) => 16,
+            TaskListError::BadId(_) => 17,
+            TaskListError::IdCollision(..) => 18,
+            TaskListError::BadTemplateName(_) => 19,
+            TaskListError::NoSuchTemplate(_) => 20,
+            TaskListError::HookFailed(..) => 21,
+            TaskListError::BadDate(_) => 22,
+            TaskListError::MissingTags => 23,
+        }
+    }
+}
+
+// A file that tasks are loaded from and saved to.
+#[derive(Clone)]
+struct Source {
     file: String,
+    // Shared with any clones so a restored snapshot saves to the same place
+    storage: Arc<Mutex<Box<dyn Storage>>>,
+    header: Header,
+}
+
+#[derive(Clone)]
+pub struct TaskList {
+    // The first file given on the command line, then any others and the files they include
+    sources: Vec<Source>,
+    // Index in `sources` of the file holding each task, by task id
+    owners: HashMap<String, usize>,
     tasks: Vec<task::Task>,
-    prefixes: HashMap<String, String>,
-    prefix_max_len: usize,
+    // Position in `tasks` by task id
+    index: HashMap<String, usize>,
+    // Ids of the sub-tasks of each task
+    children: HashMap<String, Vec<String>>,
+    // Ids of the top level tasks
+    roots: Vec<String>,
+    prefixes: PrefixIndex,
+    // Ids of tasks added, modified or removed since the last save, removed tasks along with the
+    // source they were removed from
+    changed: HashSet<String>,
+    removed: HashSet<(usize, String)>,
+    // Sources whose header changed since the last save
+    changed_headers: HashSet<usize>,
+    // Tasks keep the prefix they are first shown with
+    stable_prefixes: bool,
+    hooks: Option<Hooks>,
+    quiet: bool,
+    // Draw the tree with ASCII characters only
+    ascii: bool,
+    // Wrap descriptions to fit this many columns
+    width: Option<usize>,
+    template: Template,
+    tag_colors: HashMap<String, Color>,
+}
+
+// Which tasks `tree_rows_for` turns into rows.
+struct Visit<'a> {
+    // Tasks left out, their sub-tasks are still shown
+    hidden: &'a HashSet<String>,
+    // Tasks whose sub-tasks are left out
+    collapsed: &'a HashSet<String>,
+    // If given, only these tasks are shown
+    include: Option<&'a HashSet<String>>,
+    // Tasks already turned into rows, parents can form a cycle in a hand edited file
+    seen: HashSet<String>,
+}
+
+// Order of the tasks in a flat list.
+#[derive(Clone, Copy)]
+pub enum Sort {
+    Due,
+    Priority,
+    Created,
+    Completed,
+}
+
+// What the tasks in a flat list are grouped by.
+#[derive(Clone, Copy)]
+pub enum Group {
+    Tag,
+    Status,
+    Parent,
 }
 
-// The output is wrapped in a Result to allow matching on errors
-// Returns an Iterator to the Reader of the lines of the file.
-fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>>
-where
-    P: AsRef<Path>,
-{
-    let file = File::open(filename)?;
-    Ok(io::BufReader::new(file).lines())
+// A single line of the task tree as drawn by `show`.
+pub struct TreeRow {
+    pub id: String,
+    pub indent: String,
+    pub num_children: usize,
+    // Number of sub-tasks left out below this row, and how many of those are open
+    pub folded: Option<(usize, usize)>,
+    // Rows for sub-tasks follow this one
+    pub expanded: bool,
 }
 
 impl TaskList {
-    pub fn show(&self, hide_completed: bool) {
+    // Print the task tree down to `depth` levels.  Sub-tasks of collapsed tasks are left out.
+    pub fn show(&self, hide_completed: bool, depth: Option<usize>) {
+        self.show_tree(hide_completed, depth, &self.collapsed_ids(), None);
+    }
+
+    // Print the tasks `ids` and their ancestors as a tree, so that they are shown in context.
+    // Collapsed tasks are expanded.
+    pub fn show_matching(&self, ids: &[String], hide_completed: bool, depth: Option<usize>) {
+        let include = self.with_ancestors(ids);
+        self.show_tree(hide_completed, depth, &HashSet::new(), Some(&include));
+    }
+
+    fn show_tree(
+        &self,
+        hide_completed: bool,
+        depth: Option<usize>,
+        collapsed: &HashSet<String>,
+        include: Option<&HashSet<String>>,
+    ) {
+        let hidden = if hide_completed { self.completed_subtrees() } else { HashSet::new() };
+        let mut visit = Visit { hidden: &hidden, collapsed, include, seen: HashSet::new() };
+
         println!("Tasks:");
-        self.show_tasks(None, "│", hide_completed);
+        if self.sources.len() == 1 {
+            let mut rows = Vec::new();
+            self.tree_rows_for(&self.roots, "│", depth, &mut visit, &mut rows);
+            self.show_rows(rows);
+            return;
+        }
+
+        // Show the tasks of each file as a separate group
+        for (source, Source { file, .. }) in self.sources.iter().enumerate() {
+            let roots: Vec<String> = self
+                .roots
+                .iter()
+                .filter(|id| self.owners.get(*id) == Some(&source))
+                .cloned()
+                .collect();
+
+            let mut rows = Vec::new();
+            self.tree_rows_for(&roots, "│", depth, &mut visit, &mut rows);
+            println!("{}", file.bold());
+            self.show_rows(rows);
+        }
     }
 
-    fn show_tasks(&self, parent_id: Option<&str>, indent: &str, hide_completed: bool) {
+    // Print the tasks `ids` one per line, without the tree but followed by the descriptions of
+    // their ancestors.
+    pub fn show_flat(
+        &self,
+        ids: &[String],
+        hide_completed: bool,
+        sort: Sort,
+        group: Option<Group>,
+    ) {
+        let mut tasks: Vec<&task::Task> = ids
+            .iter()
+            .filter_map(|id| self.task_by_id(id))
+            .filter(|task| !(hide_completed && task.is_completed()))
+            .collect();
+        match sort {
+            // Tasks without a due date or priority go last
+            Sort::Due => tasks.sort_by(|a, b| {
+                let key =
+                    |t: &task::Task| (t.due().is_none(), t.due().map(String::from), t.timestamp());
+                key(a).partial_cmp(&key(b)).unwrap()
+            }),
+            Sort::Priority => tasks.sort_by(|a, b| {
+                let key = |t: &task::Task| (t.priority().is_none(), t.priority(), t.timestamp());
+                key(a).partial_cmp(&key(b)).unwrap()
+            }),
+            Sort::Created => {
+                tasks.sort_by(|a, b| a.timestamp().partial_cmp(&b.timestamp()).unwrap())
+            }
+            // Open tasks go last
+            Sort::Completed => tasks.sort_by(|a, b| {
+                let key =
+                    |t: &task::Task| (!t.is_completed(), t.completed_timestamp(), t.timestamp());
+                key(a).partial_cmp(&key(b)).unwrap()
+            }),
+        }
+
+        // Groups in the order they first appear in, a task with several tags is in each of their
+        // groups
+        let mut groups: Vec<(String, Vec<&task::Task>)> = Vec::new();
+        for task in tasks {
+            for name in self.group_names(task, group) {
+                match groups.iter_mut().find(|(group_name, _)| *group_name == name) {
+                    Some((_, members)) => members.push(task),
+                    None => groups.push((name, vec![task])),
+                }
+            }
+        }
+
+        println!("Tasks:");
+        for (name, tasks) in groups {
+            let indent = match group {
+                Some(_) => {
+                    println!("{}", name);
+                    "  "
+                }
+                None => "",
+            };
+            for task in tasks {
+                println!("{}{}", indent, self.format_flat(task));
+            }
+        }
+    }
+
+    // The groups `task` is listed under by `show_flat`.
+    fn group_names(&self, task: &task::Task, group: Option<Group>) -> Vec<String> {
+        match group {
+            None => vec![String::new()],
+            Some(Group::Tag) if task.tags().is_empty() => vec!["untagged".bold().to_string()],
+            Some(Group::Tag) => task.tags().iter().map(|tag| self.tag_label(tag)).collect(),
+            Some(Group::Status) if task.is_completed() => vec!["completed".bold().to_string()],
+            Some(Group::Status) => vec!["open".bold().to_string()],
+            Some(Group::Parent) => {
+                match task.parent_id().as_deref().and_then(|id| self.task_by_id(id)) {
+                    Some(parent) => vec![self.label(parent)],
+                    None => vec!["top level".bold().to_string()],
+                }
+            }
+        }
+    }
+
+    fn format_flat(&self, task: &task::Task) -> String {
+        let row = TreeRow {
+            id: task.id().to_string(),
+            indent: String::new(),
+            num_children: self.child_ids(Some(task.id())).len(),
+            folded: None,
+            expanded: false,
+        };
+        let mut line = self.format_row(&row, task).trim_start().to_string();
+
+        let ancestors: Vec<String> =
+            self.ancestors(task).into_iter().map(|ancestor| self.label(ancestor)).collect();
+        if !ancestors.is_empty() {
+            let separator = " > ".dimmed().to_string();
+            line += &format!("  {}{}{}", "(".dimmed(), ancestors.join(&separator), ")".dimmed());
+        }
+        line
+    }
+
+    // Print everything known about a single task, then the task and its sub-tasks down to `depth`
+    // levels.
+    pub fn show_task(
+        &self,
+        prefix: &str,
+        hide_completed: bool,
+        depth: Option<usize>,
+    ) -> Result<(), TaskListError> {
+        let full_id = self.get_full_id(prefix)?;
+        let task = self.get_task_ref(&full_id)?;
+        let ancestors: Vec<String> =
+            self.ancestors(task).into_iter().map(|ancestor| self.label(ancestor)).collect();
+
+        let tags: Vec<String> = task.tags().iter().map(|tag| self.tag_label(tag)).collect();
+
+        let open = |ids: &[String]| {
+            ids.iter().filter(|id| self.task_by_id(id).is_some_and(|t| !t.is_completed())).count()
+        };
+        let children = self.child_ids(Some(&full_id));
+        let descendants = self.descendant_ids(&full_id);
+
+        println!("{}", self.label(task));
+        println!("  id:        {}", task.id());
+        println!("  prefix:    {}", self.prefixes.prefix(&full_id).unwrap_or(&full_id));
+        println!(
+            "  parent:    {}",
+            if ancestors.is_empty() { "-".to_string() } else { ancestors.join(" > ") }
+        );
+        println!("  tags:      {}", if tags.is_empty() { "-".to_string() } else { tags.join(" ") });
+        println!("  due:       {}", task.due().unwrap_or("-"));
+        println!("  priority:  {}", task.priority().map_or("-", |priority| priority.name()));
+        // Tasks added before creation times were kept have 0
+        if task.timestamp() > 0.0 {
+            println!("  created:   {}", task::format_timestamp(task.timestamp()));
+        } else {
+            println!("  created:   -");
+        }
+        if task.is_completed() {
+            println!("  completed: {}", task::format_timestamp(task.completed_timestamp()));
+        } else {
+            println!("  completed: -");
+        }
+        println!(
+            "  sub-tasks: {} ({} open), {} including their sub-tasks ({} open)",
+            children.len(),
+            open(children),
+            descendants.len(),
+            open(&descendants)
+        );
+        println!();
+
+        // The task itself is shown expanded even if it is collapsed
+        let hidden = if hide_completed { self.completed_subtrees() } else { HashSet::new() };
+        let mut collapsed = self.collapsed_ids();
+        collapsed.remove(&full_id);
+        let mut visit =
+            Visit { hidden: &hidden, collapsed: &collapsed, include: None, seen: HashSet::new() };
+        let mut rows = Vec::new();
+        self.tree_rows_for(&[full_id], "│", depth, &mut visit, &mut rows);
+        self.show_rows(rows);
+
+        Ok(())
+    }
+
+    fn show_rows(&self, rows: Vec<TreeRow>) {
+        for row in rows {
+            if let Ok(task) = self.get_task_ref(&row.id) {
+                for line in self.format_lines(&row, task) {
+                    println!("{}", line);
+                }
+            }
+        }
+    }
+
+    // A task as named in headers and breadcrumbs, e.g. '3f: Backend'.
+    fn label(&self, task: &task::Task) -> String {
+        let prefix = self.prefixes.prefix(task.id()).unwrap_or(task.id());
+        format!("{}: {}", prefix.yellow().bold(), task.desc())
+    }
+
+    // The parent of `task`, its parent and so on, top level first.
+    fn ancestors(&self, task: &task::Task) -> Vec<&task::Task> {
+        let mut ancestors = Vec::new();
+        let mut seen = HashSet::from([task.id()]);
+        let mut parent_id = task.parent_id().as_deref();
+        while let Some(parent) = parent_id.and_then(|id| self.task_by_id(id)) {
+            // Parents can form a cycle in a hand edited file
+            if !seen.insert(parent.id()) {
+                break;
+            }
+            ancestors.push(parent);
+            parent_id = parent.parent_id().as_deref();
+        }
+        ancestors.reverse();
+        ancestors
+    }
+
+    // The tasks `ids` along with all of their ancestors.
+    pub fn with_ancestors(&self, ids: &[String]) -> HashSet<String> {
+        let mut include = HashSet::new();
+        for id in ids {
+            let mut id = Some(id.as_str());
+            while let Some(ancestor_id) = id {
+                if !include.insert(ancestor_id.to_string()) {
+                    break;
+                }
+                id = self.task_by_id(ancestor_id).and_then(|t| t.parent_id().as_deref());
+            }
+        }
+        include
+    }
+
+    // Ids of the tasks marked as collapsed.
+    pub fn collapsed_ids(&self) -> HashSet<String> {
+        self.tasks.iter().filter(|t| t.is_collapsed()).map(|t| t.id().to_string()).collect()
+    }
+
+    // Flatten the task tree into the rows that `show` prints.  Children of tasks in `collapsed`
+    // are skipped and, if `include` is given, only tasks in that set are visited.
+    pub fn tree_rows(
+        &self,
+        hide_completed: bool,
+        collapsed: &HashSet<String>,
+        include: Option<&HashSet<String>>,
+    ) -> Vec<TreeRow> {
+        let hidden = if hide_completed { self.completed_subtrees() } else { HashSet::new() };
+
+        let mut visit = Visit { hidden: &hidden, collapsed, include, seen: HashSet::new() };
+        let mut rows = Vec::new();
+        self.tree_rows_for(&self.roots, "│", None, &mut visit, &mut rows);
+        rows
+    }
+
+    // Rows for the tasks `ids`, all sharing a parent, and their sub-tasks down to `depth` levels.
+    fn tree_rows_for(
+        &self,
+        ids: &[String],
+        indent: &str,
+        depth: Option<usize>,
+        visit: &mut Visit,
+        rows: &mut Vec<TreeRow>,
+    ) {
+        if depth == Some(0) {
+            return;
+        }
+
         let mut sorted_tasks = Vec::new();
-        for task in &self.tasks {
-            if task.parent_id().as_deref() == parent_id {
-                sorted_tasks.push(task);
+        for id in ids {
+            if visit.include.is_none_or(|include| include.contains(id)) {
+                if let Some(task) = self.task_by_id(id) {
+                    sorted_tasks.push(task);
+                }
             }
         }
         sorted_tasks.sort_by(|a, b| a.timestamp().partial_cmp(&b.timestamp()).unwrap());
         let num_tasks = sorted_tasks.len();
         for (ii, task) in sorted_tasks.iter().enumerate() {
             let last_task = ii == num_tasks - 1;
-            if let Some(prefix) = self.prefixes.get(task.id()) {
-                // Create list of tags, e.g. [tag1] [tag2] [tag3]
-                let mut tags = "".to_string();
-                for tag in task.tags() {
-                    let _ = write!(tags, "[{}] ", tag.red());
-                }
-
+            if !visit.seen.insert(task.id().to_string()) {
+                continue;
+            }
+            if self.prefixes.prefix(task.id()).is_some() {
                 let indent_item = {
                     let mut a = indent.to_string();
                     a.pop();
@@ -83,28 +564,25 @@ impl TaskList {
                     }
                 };
 
-                let checkmark = {
-                    if task.is_completed() {
-                        //"☒"
-                        format!("[{}]", "X".bold())
-                    } else {
-                        //"☐"
-                        "[ ]".to_string()
-                    }
-                };
+                let num_children = self.child_ids(Some(task.id())).len();
+
+                // Sub-tasks are left out of collapsed tasks and below the last level
+                let folded =
+                    num_children > 0 && (visit.collapsed.contains(task.id()) || depth == Some(1));
+
+                let pos = rows.len();
+                if !visit.hidden.contains(task.id()) {
+                    rows.push(TreeRow {
+                        id: task.id().to_string(),
+                        indent: indent_item,
+                        num_children,
+                        folded: if folded { self.folded_counts(task.id(), visit) } else { None },
+                        expanded: false,
+                    });
+                }
 
-                if !hide_completed
-                    || !task.is_completed()
-                    || !self.all_descendants_completed(task.id())
-                {
-                    println!(
-                        "{} {} {}: {}{}",
-                        indent_item,
-                        checkmark,
-                        prefix.yellow().bold(),
-                        tags,
-                        task.desc()
-                    );
+                if folded {
+                    continue;
                 }
 
                 let next_indent = if last_task {
@@ -115,128 +593,712 @@ impl TaskList {
                     indent.to_string() + "   │"
                 };
 
-                self.show_tasks(Some(task.id()), &next_indent, hide_completed);
+                let child_ids = self.child_ids(Some(task.id()));
+                let depth = depth.map(|depth| depth - 1);
+                self.tree_rows_for(child_ids, &next_indent, depth, visit, rows);
+
+                if !visit.hidden.contains(task.id()) {
+                    rows[pos].expanded = rows.len() > pos + 1;
+                }
             }
         }
     }
 
+    // Number of sub-tasks of `id` that would have been shown if it was expanded, and how many of
+    // them are open.
+    fn folded_counts(&self, id: &str, visit: &Visit) -> Option<(usize, usize)> {
+        let descendants: Vec<&task::Task> = self
+            .descendant_ids(id)
+            .iter()
+            .filter(|id| !visit.hidden.contains(*id))
+            .filter(|id| visit.include.is_none_or(|include| include.contains(*id)))
+            .filter_map(|id| self.task_by_id(id))
+            .collect();
+
+        match descendants.len() {
+            0 => None,
+            count => Some((count, descendants.iter().filter(|t| !t.is_completed()).count())),
+        }
+    }
+
+    // Ids of completed tasks whose descendants are all completed too.
+    fn completed_subtrees(&self) -> HashSet<String> {
+        let mut visited = HashSet::new();
+        let mut completed = HashSet::new();
+        for id in &self.roots {
+            self.find_completed_subtrees(id, &mut visited, &mut completed);
+        }
+        completed
+    }
+
+    fn find_completed_subtrees(
+        &self,
+        id: &str,
+        visited: &mut HashSet<String>,
+        completed: &mut HashSet<String>,
+    ) -> bool {
+        if !visited.insert(id.to_string()) {
+            return completed.contains(id);
+        }
+        let mut all_completed = self.task_by_id(id).is_some_and(|task| task.is_completed());
+        for child_id in self.child_ids(Some(id)) {
+            // Visit every child, not just up to the first open one
+            all_completed &= self.find_completed_subtrees(child_id, visited, completed);
+        }
+
+        if all_completed {
+            completed.insert(id.to_string());
+        }
+        all_completed
+    }
+
+    pub fn format_row(&self, row: &TreeRow, task: &task::Task) -> String {
+        let value = |field| self.field(row, task, field);
+        let mut text = self.template.lead(value) + &self.template.body(value);
+        if let Some(folded) = folded_summary(row) {
+            text += &format!(" {}", folded);
+        }
+        text
+    }
+
+    // The lines printed for a row, the description is wrapped to the width set with `set_width`.
+    fn format_lines(&self, row: &TreeRow, task: &task::Task) -> Vec<String> {
+        let width = match self.width {
+            Some(width) => width,
+            None => return vec![self.format_row(row, task)],
+        };
+        let lead = self.template.lead(|field| self.field(row, task, field));
+
+        // Wrapped lines carry on the guides of the tree, including the one down to the sub-tasks,
+        // and line up with the text after the lead
+        let tree = self.template.has(Field::Indent);
+        let guide = self.template.guide(|field| {
+            let guide = match field {
+                Field::Indent => row.indent.replace("├─", "│ ").replace("└─", "  "),
+                Field::Checkmark if tree && row.expanded => " │ ".to_string(),
+                _ => " ".repeat(3),
+            };
+            if self.ascii {
+                ascii_guides(&guide)
+            } else {
+                guide
+            }
+        });
+        let columns = width.saturating_sub(template::visible_width(&lead)).max(MIN_WRAP_WIDTH);
+
+        let body = self.template.body(|field| self.field(row, task, field));
+        let mut words: Vec<String> =
+            body.split(' ').filter(|word| !word.is_empty()).map(String::from).collect();
+        words.extend(folded_summary(row));
+
+        let mut lines = vec![lead];
+        let mut used = 0;
+        for word in words {
+            let word_width = template::visible_width(&word);
+            if used > 0 && used + 1 + word_width > columns {
+                lines.push(guide.clone());
+                used = 0;
+            }
+            let line = lines.last_mut().unwrap();
+            if used > 0 {
+                line.push(' ');
+                used += 1;
+            }
+            line.push_str(&word);
+            used += word_width;
+        }
+        lines
+    }
+
+    // The value of a template field for a row.
+    fn field(&self, row: &TreeRow, task: &task::Task, field: Field) -> String {
+        match field {
+            Field::Indent if self.ascii => ascii_guides(&row.indent),
+            Field::Indent => row.indent.clone(),
+            Field::Checkmark => {
+                if task.is_completed() {
+                    //"☒"
+                    format!("[{}]", "X".bold())
+                } else {
+                    //"☐"
+                    "[ ]".to_string()
+                }
+            }
+            Field::Prefix => {
+                let prefix = self.prefixes.prefix(task.id()).unwrap_or(task.id());
+                prefix.yellow().bold().to_string()
+            }
+            Field::Id => task.id().to_string(),
+            // Tags, e.g. [tag1] [tag2] [tag3]
+            Field::Tags => task.tags().iter().map(|tag| self.tag_label(tag) + " ").collect(),
+            Field::Desc => task.desc().to_string(),
+            Field::Age if task.timestamp() == 0.0 => "-".to_string(),
+            Field::Age => {
+                let days = (task::now() - task.timestamp()).max(0.0) / (24.0 * 60.0 * 60.0);
+                format!("{}d", days as u64)
+            }
+            Field::Due => task.due().unwrap_or_default().to_string(),
+            Field::Priority => task.priority().map_or("", |priority| priority.name()).to_string(),
+            Field::Status => if task.is_completed() { "completed" } else { "open" }.to_string(),
+            Field::Progress => {
+                let descendants = self.descendant_ids(task.id());
+                let completed = descendants
+                    .iter()
+                    .filter(|id| self.task_by_id(id).is_some_and(|t| t.is_completed()))
+                    .count();
+                match descendants.len() {
+                    0 => String::new(),
+                    count => format!("{}/{}", completed, count),
+                }
+            }
+        }
+    }
+
+    // A tag as shown next to a task, e.g. [tag1], in its configured color.
+    fn tag_label(&self, tag: &str) -> String {
+        let color = self.tag_colors.get(tag).cloned().unwrap_or(Color::Red);
+        format!("[{}]", tag.color(color))
+    }
+
+    // The main task file, new top level tasks are added to it.
+    pub fn file(&self) -> &str {
+        &self.sources[0].file
+    }
+
+    // The main task file and the files it includes.
+    pub fn files(&self) -> Vec<&str> {
+        self.sources.iter().map(|source| source.file.as_str()).collect()
+    }
+
+    pub fn set_quiet(&mut self, quiet: bool) {
+        self.quiet = quiet;
+    }
+
+    // Whether to leave out messages about what a command did.
+    pub fn quiet(&self) -> bool {
+        self.quiet
+    }
+
+    pub fn set_ascii(&mut self, ascii: bool) {
+        self.ascii = ascii;
+    }
+
+    pub fn ascii(&self) -> bool {
+        self.ascii
+    }
+
+    // Wrap descriptions shown by `show` and `show_task` to `width` columns, or not at all.
+    pub fn set_width(&mut self, width: Option<usize>) {
+        self.width = width;
+    }
+
+    pub fn set_template(&mut self, template: Template) {
+        self.template = template;
+    }
+
+    // Run the hooks in `dir` for changes made from now on.
+    pub fn set_hooks(&mut self, dir: PathBuf) {
+        self.hooks = Some(Hooks::new(dir, &self.tasks));
+    }
+
+    pub fn set_tag_colors(&mut self, tag_colors: HashMap<String, Color>) {
+        self.tag_colors = tag_colors;
+    }
+
+    pub fn tasks(&self) -> &Vec<task::Task> {
+        &self.tasks
+    }
+
+    pub fn prefix(&self, id: &str) -> Option<&String> {
+        self.prefixes.prefix(id)
+    }
+
     pub fn add_task(
         &mut self,
         parent_id: Option<&str>,
         id: Option<&str>,
         desc: &str,
-    ) -> Result<(), TaskListError> {
-        // Check if task with this user specified id already exists
+    ) -> Result<String, TaskListError> {
+        // Check the user specified id
         if let Some(id) = id {
-            if let Ok(task) = self.get_task(id) {
-                if id == task.id() {
-                    return Err(TaskListError::DuplicateTask);
-                }
-            }
+            self.check_custom_id(id, None)?;
         }
 
         // Check if parent exists
-        let full_parent_id;
-        match parent_id {
-            Some(parent_id) => match self.get_full_id(parent_id) {
-                Ok(full_id) => {
-                    full_parent_id = Some(full_id);
-                }
-                Err(_) => {
-                    return Err(TaskListError::BadParentPrefix);
-                }
+        let full_parent_id = self.parent_full_id(parent_id)?;
+
+        let task_id = self.create_task(full_parent_id.as_deref(), id, desc);
+
+        // Show user added Task information
+        let task_prefix: String = match self.prefixes.prefix(&task_id) {
+            Some(prefix) => prefix.to_string(),
+            None => task_id.to_string(),
+        };
+        if !self.quiet {
+            println!("added task {} ({})", task_prefix, task_id);
+        }
+
+        Ok(task_id)
+    }
+
+    // Full id of the parent given as `parent`, if any.
+    fn parent_full_id(&self, parent: Option<&str>) -> Result<Option<String>, TaskListError> {
+        match parent {
+            Some(parent) => match self.get_full_id(parent) {
+                Ok(full_id) => Ok(Some(full_id)),
+                Err(e @ TaskListError::AmbiguousPrefix(..)) => Err(e),
+                Err(_) => Err(TaskListError::BadParentPrefix),
             },
-            None => {
-                full_parent_id = None;
-            }
+            None => Ok(None),
         }
+    }
+
+    // Create a task under an existing parent and return its id.
+    fn create_task(
+        &mut self,
+        full_parent_id: Option<&str>,
+        id: Option<&str>,
+        desc: &str,
+    ) -> String {
+        // Add Task to Task List, in the same file as its parent
+        let source = self.owner_of(full_parent_id);
 
-        // Create Task
-        let task = task::create(full_parent_id.as_deref(), id, desc);
+        // Create Task, numbered if its file numbers tasks
+        let sequential_id = match id {
+            None => self.take_sequential_id(source),
+            Some(_) => None,
+        };
+        let id = id.or(sequential_id.as_deref());
+        let mut task = task::create(full_parent_id, id, desc);
+
+        // Tasks with the same description created within the resolution of the clock would get
+        // the same hash
+        while id.is_none() && self.index.contains_key(task.id()) {
+            task = task::create(full_parent_id, id, desc);
+        }
         let task_id = task.id().to_string();
 
-        // Add Task to Task List
+        self.prefixes.insert(&task_id, task.show_full_id());
         self.tasks.push(task);
-        self.compute_prefixes();
+        self.index_task(self.tasks.len() - 1);
+        self.set_owner(&task_id, source);
+        self.issue_prefix(&task_id);
 
-        // Show user added Task information
-        let task_prefix: String = match self.prefixes.get(&task_id) {
-            Some(prefix) => prefix.to_string(),
-            None => task_id.to_string(),
+        task_id
+    }
+
+    // Copies of the task `prefix` and all of its sub-tasks, each after its parent and in the
+    // order they were created.
+    pub fn subtree(&self, prefix: &str) -> Result<Vec<task::Task>, TaskListError> {
+        let full_id = self.get_full_id(prefix)?;
+
+        let mut tasks = Vec::new();
+        let mut seen = HashSet::new();
+        let mut stack = vec![full_id];
+        while let Some(id) = stack.pop() {
+            if !seen.insert(id.to_string()) {
+                continue;
+            }
+            if let Some(task) = self.task_by_id(&id) {
+                tasks.push(task.clone());
+            }
+            let mut children: Vec<&task::Task> =
+                self.child_ids(Some(&id)).iter().filter_map(|id| self.task_by_id(id)).collect();
+            children.sort_by(|a, b| b.timestamp().partial_cmp(&a.timestamp()).unwrap());
+            stack.extend(children.iter().map(|child| child.id().to_string()));
+        }
+
+        Ok(tasks)
+    }
+
+    // Add copies of `tasks`, as returned by `subtree`, with new ids.  Tasks whose parent is not
+    // among them are added under `parent`.  Descriptions, tags and folding are kept, and so is
+    // completion unless `reset` is set.  Returns the ids of the copies.
+    pub fn add_copies(
+        &mut self,
+        tasks: &[task::Task],
+        parent: Option<&str>,
+        reset: bool,
+    ) -> Result<Vec<String>, TaskListError> {
+        let full_parent_id = self.parent_full_id(parent)?;
+
+        let mut new_ids: HashMap<&str, String> = HashMap::new();
+        let mut copies = Vec::new();
+        for task in tasks {
+            let parent_id = match task.parent_id().as_deref().and_then(|id| new_ids.get(id)) {
+                Some(new_parent_id) => Some(new_parent_id.to_string()),
+                None => full_parent_id.clone(),
+            };
+            let id = self.create_task(parent_id.as_deref(), None, task.desc());
+
+            let copy = self.get_task(&id)?;
+            copy.set_tags(task.tags().clone());
+            copy.set_due(task.due());
+            copy.set_priority(task.priority());
+            copy.set_collapsed(task.is_collapsed());
+            if !reset {
+                copy.set_completed_timestamp(task.completed_timestamp());
+            }
+
+            new_ids.insert(task.id(), id.to_string());
+            copies.push(id);
+        }
+
+        Ok(copies)
+    }
+
+    // Give a task a new custom id and update the parent ids of its sub-tasks.  To `sync` and
+    // `diff` the task is removed under its old id and added under the new one.
+    pub fn rename_task(&mut self, prefix: &str, new_id: &str) -> Result<(), TaskListError> {
+        let full_id = self.get_full_id(prefix)?;
+        if full_id == new_id {
+            return Ok(());
+        }
+        self.check_custom_id(new_id, Some(&full_id))?;
+
+        let mut task = self.get_task_ref(&full_id)?.clone();
+        let source = self.owner_of(Some(&full_id));
+        let child_ids = self.child_ids(Some(&full_id)).to_vec();
+
+        self.unlink_task(&full_id);
+        task.set_id(new_id);
+        self.prefixes.insert(new_id, true);
+        self.tasks.push(task);
+        self.index_task(self.tasks.len() - 1);
+        self.set_owner(new_id, source);
+        self.issue_prefix(new_id);
+
+        for child_id in child_ids {
+            if let Some(child) = self.task_by_id(&child_id) {
+                let mut child = child.clone();
+                child.set_parent_id(Some(new_id));
+                self.upsert_task(child);
+            }
+        }
+
+        if !self.quiet {
+            println!("renamed task {} ({}) to {}", prefix, full_id, new_id);
+        }
+
+        Ok(())
+    }
+
+    // The next free id of the form #N for a task added to `source`, if it numbers its tasks.
+    fn take_sequential_id(&mut self, source: usize) -> Option<String> {
+        let next_id = self.sources[source].header.next_id?;
+
+        // Skip numbers already taken by tasks in other files
+        let mut number = next_id;
+        while self.index.contains_key(&format!("#{}", number)) {
+            number += 1;
+        }
+        self.sources[source].header.next_id = Some(number + 1);
+        self.changed_headers.insert(source);
+
+        Some(format!("#{}", number))
+    }
+
+    // The number of the next task added to the main file, if it numbers its tasks rather than
+    // using hashes for ids.
+    pub fn next_id(&self) -> Option<u64> {
+        self.sources[0].header.next_id
+    }
+
+    // Number new tasks in the main file #1, #2 and so on, carrying on from the highest number in
+    // use, or go back to hashes.  Existing ids are left as they are.
+    pub fn set_sequential_ids(&mut self, sequential: bool) {
+        let next_id = match sequential {
+            true => {
+                let highest = self
+                    .tasks
+                    .iter()
+                    .filter_map(|task| task.id().strip_prefix('#'))
+                    .filter_map(|number| number.parse::<u64>().ok())
+                    .max();
+                Some(self.next_id().or(highest.map(|n| n + 1)).unwrap_or(1))
+            }
+            false => None,
         };
-        println!("added task {} ({})", task_prefix, task_id);
+
+        if self.sources[0].header.next_id != next_id {
+            self.sources[0].header.next_id = next_id;
+            self.changed_headers.insert(0);
+        }
+    }
+
+    // Keep the prefix each task is shown with from now on.  A prefix is only made longer when
+    // another task was given the same one, e.g. in another copy of the list synced with this one,
+    // and a task's earlier prefixes still find it while no other task had them.
+    pub fn set_stable_prefixes(&mut self) {
+        self.stable_prefixes = true;
+        for task in &self.tasks {
+            self.prefixes.set_stable(task.id(), task.prefixes());
+        }
+
+        let ids: Vec<String> = self.tasks.iter().map(|task| task.id().to_string()).collect();
+        for id in ids {
+            self.issue_prefix(&id);
+        }
+    }
+
+    // Give a task a stable prefix, if it needs one.
+    fn issue_prefix(&mut self, id: &str) {
+        if !self.stable_prefixes {
+            return;
+        }
+        if let Some(prefix) = self.prefixes.issue(id) {
+            if let Some(&pos) = self.index.get(id) {
+                self.tasks[pos].add_prefix(&prefix);
+                self.changed.insert(id.to_string());
+            }
+        }
+    }
+
+    // Custom ids are limited to characters that are safe on the command line and in the task
+    // file, and must not be prefixes of other ids, or other ids prefixes of them, so that every
+    // id can still be typed in full.  `renamed` is the id of the task being given the id.
+    fn check_custom_id(&self, id: &str, renamed: Option<&str>) -> Result<(), TaskListError> {
+        let valid = |c: char| c.is_ascii_alphanumeric() || "_.-".contains(c);
+        if id.is_empty() || id.starts_with('-') || !id.chars().all(valid) {
+            return Err(TaskListError::BadId(id.to_string()));
+        }
+
+        if self.index.contains_key(id) {
+            return Err(TaskListError::DuplicateTask);
+        }
+
+        let longer = match self.prefixes.lookup(id) {
+            Lookup::Unique(other) => vec![other.to_string()],
+            Lookup::Ambiguous(others) => others.to_vec(),
+            Lookup::NotFound => Vec::new(),
+        };
+        let shorter = (1..id.len()).map(|len| &id[..len]).filter(|p| self.index.contains_key(*p));
+        let collision = longer
+            .iter()
+            .map(|other| other.as_str())
+            .chain(shorter)
+            .find(|other| Some(*other) != renamed);
+        match collision {
+            Some(other) => {
+                let other = self.prefixes.prefix(other).map_or(other, |p| p.as_str());
+                Err(TaskListError::IdCollision(id.to_string(), other.to_string()))
+            }
+            None => Ok(()),
+        }
+    }
+
+    // Save every file with added, changed or removed tasks, after running any hooks for them.
+    pub fn save(&mut self) -> Result<(), TaskListError> {
+        let ids: BTreeSet<String> = self
+            .changed
+            .iter()
+            .cloned()
+            .chain(self.removed.iter().map(|(_, id)| id.to_string()))
+            .collect();
+        if let Some(hooks) = &self.hooks {
+            for id in &ids {
+                hooks.run(id, self.task_by_id(id))?;
+            }
+        }
+
+        // Forget old removals
+        let cutoff = task::now() - TOMBSTONE_DAYS * 24.0 * 60.0 * 60.0;
+        for (index, source) in self.sources.iter_mut().enumerate() {
+            let count = source.header.tombstones.len();
+            source.header.tombstones.retain(|_, timestamp| *timestamp >= cutoff);
+            if source.header.tombstones.len() != count {
+                self.changed_headers.insert(index);
+            }
+        }
+
+        for (index, source) in self.sources.iter().enumerate() {
+            let owned = |id: &String| self.owners.get(id) == Some(&index);
+
+            let changed: Vec<&task::Task> = self
+                .changed
+                .iter()
+                .filter(|id| owned(id))
+                .filter_map(|id| self.task_by_id(id))
+                .collect();
+            let removed: Vec<String> = self
+                .removed
+                .iter()
+                .filter(|(removed_from, _)| *removed_from == index)
+                .map(|(_, id)| id.to_string())
+                .collect();
+            if changed.is_empty() && removed.is_empty() && !self.changed_headers.contains(&index) {
+                continue;
+            }
+
+            let mut storage = match source.storage.lock() {
+                Ok(storage) => storage,
+                Err(_) => {
+                    return Err(TaskListError::StorageError("storage unavailable".to_string()))
+                }
+            };
+            let tasks: Vec<&task::Task> = self.tasks.iter().filter(|t| owned(t.id())).collect();
+            storage.save_changes(&source.header, &tasks, &changed, &removed)?;
+        }
+
+        let saved: Vec<(String, Option<task::Task>)> =
+            ids.into_iter().map(|id| (id.to_string(), self.task_by_id(&id).cloned())).collect();
+        if let Some(hooks) = &mut self.hooks {
+            for (id, task) in saved {
+                hooks.saved(&id, task);
+            }
+        }
+        self.changed.clear();
+        self.removed.clear();
+        self.changed_headers.clear();
 
         Ok(())
     }
 
-    fn compute_prefixes(&mut self) {
-        // Create shortest ids for each task
-        // Note: this is a crude, slow implementation (but it works)
-        self.prefix_max_len = 1;
-        self.prefixes = HashMap::new();
-        for (pos, task) in self.tasks.iter().enumerate() {
-            let mut len = 1;
-            let mut unique = false;
-            let mut prefix = "";
+    // Files included by the main task file.
+    pub fn includes(&self) -> &[String] {
+        &self.sources[0].header.includes
+    }
 
-            if task.show_full_id() {
-                prefix = task.id();
-                len = prefix.len();
-            } else {
-                while !unique && len <= task.id().len() {
-                    prefix = &task.id()[..len];
-                    unique = true;
-                    for (pos2, task2) in self.tasks.iter().enumerate() {
-                        if pos == pos2 {
-                            continue;
-                        }
-
-                        if task2.id().starts_with(prefix) {
-                            unique = false;
-                            len += 1;
-                            break;
-                        }
+    // Whether the main task file has been synced.
+    pub fn synced(&self) -> bool {
+        self.sources[0].header.synced
+    }
+
+    // Record removals as tombstones in every file from now on, so that `sync` can carry them over.
+    pub fn set_synced(&mut self) {
+        for (index, source) in self.sources.iter_mut().enumerate() {
+            if !source.header.synced {
+                source.header.synced = true;
+                self.changed_headers.insert(index);
+            }
+        }
+    }
+
+    // When each removed task was removed, by id, across all files.
+    pub fn tombstones(&self) -> BTreeMap<String, f64> {
+        let mut tombstones = BTreeMap::new();
+        for source in &self.sources {
+            for (id, timestamp) in &source.header.tombstones {
+                let entry = tombstones.entry(id.to_string()).or_insert(*timestamp);
+                *entry = entry.max(*timestamp);
+            }
+        }
+        tombstones
+    }
+
+    // Add `task` as is, or replace the task with the same id.
+    pub fn upsert_task(&mut self, task: task::Task) {
+        let id = task.id().to_string();
+
+        match self.index.get(&id).cloned() {
+            Some(pos) => {
+                let old_parent_id = self.tasks[pos].parent_id().clone();
+                self.unlink_from_parent(&id, old_parent_id.as_deref());
+                self.tasks[pos] = task;
+                match self.tasks[pos].parent_id() {
+                    Some(parent_id) => {
+                        self.children.entry(parent_id.to_string()).or_default().push(id.to_string())
                     }
+                    None => self.roots.push(id.to_string()),
                 }
             }
-            if len > self.prefix_max_len {
-                self.prefix_max_len = len
+            None => {
+                let source = self.owner_of(task.parent_id().as_deref());
+                self.prefixes.insert(&id, task.show_full_id());
+                if self.stable_prefixes {
+                    self.prefixes.set_stable(&id, task.prefixes());
+                }
+                self.tasks.push(task);
+                self.index_task(self.tasks.len() - 1);
+                self.set_owner(&id, source);
+                self.issue_prefix(&id);
             }
-            self.prefixes.insert(task.id().to_string(), prefix.to_string());
         }
+
+        self.changed.insert(id);
+    }
+
+    // Remove the task with exactly this id, if any, and record it as removed at `timestamp`.
+    // Its sub-tasks are left alone.
+    pub fn delete_task(&mut self, id: &str, timestamp: f64) {
+        let source = self.owner_of(Some(id));
+        self.unlink_task(id);
+        self.add_tombstone(source, id, timestamp);
+        self.changed.remove(id);
+        self.removed.insert((source, id.to_string()));
     }
 
-    pub fn save(&self) {
-        let file = File::create(&self.file).expect("cannot open file for write");
-        let mut file = BufWriter::new(file);
+    pub fn move_task(&mut self, prefix: &str, parent: Option<&str>) -> Result<(), TaskListError> {
+        let full_id = self.get_full_id(prefix)?;
 
-        let mut sorted_tasks = self.tasks.to_vec();
-        sorted_tasks.sort_by(|a, b| a.id().partial_cmp(b.id()).unwrap());
+        let full_parent_id = match parent {
+            Some(parent) => match self.get_full_id(parent) {
+                Ok(full_parent_id) => Some(full_parent_id),
+                Err(e @ TaskListError::AmbiguousPrefix(..)) => return Err(e),
+                Err(_) => return Err(TaskListError::BadParentPrefix),
+            },
+            None => None,
+        };
 
-        for task in &sorted_tasks {
-            file.write_all((task.to_file_string() + "\n").as_bytes()).expect("cannot write data");
+        // Walk up from the new parent to make sure we are not creating a cycle
+        let mut ancestor = full_parent_id.clone();
+        while let Some(ancestor_id) = ancestor {
+            if ancestor_id == full_id {
+                return Err(TaskListError::MoveIntoDescendant);
+            }
+            ancestor = self.get_task_ref(&ancestor_id)?.parent_id().clone();
         }
+
+        let old_parent_id = self.get_task_ref(&full_id)?.parent_id().clone();
+        self.unlink_from_parent(&full_id, old_parent_id.as_deref());
+
+        let task = self.get_task(&full_id)?;
+        task.set_parent_id(full_parent_id.as_deref());
+        match &full_parent_id {
+            Some(parent_id) => {
+                self.children.entry(parent_id.to_string()).or_default().push(full_id.to_string())
+            }
+            None => self.roots.push(full_id.to_string()),
+        }
+
+        // Sub-tasks are kept in the same file as their parent
+        if full_parent_id.is_some() {
+            let source = self.owner_of(full_parent_id.as_deref());
+            self.set_owner(&full_id, source);
+            for id in self.descendant_ids(&full_id) {
+                self.set_owner(&id, source);
+            }
+        }
+
+        if !self.quiet {
+            println!("moved task {} ({})", prefix, full_id);
+        }
+
+        Ok(())
     }
 
     pub fn remove_task(&mut self, prefix: &str, force: bool) -> Result<(), TaskListError> {
         let full_id = self.get_full_id(prefix)?;
 
-        let children = self.get_children_tasks(&full_id)?;
-        let children_ids: Vec<String> = children.into_iter().map(|c| c.id().to_string()).collect();
-        if !children_ids.is_empty() {
-            if force {
-                for id in &children_ids {
-                    self.remove_task(id, force)?;
-                }
-            } else {
-                return Err(TaskListError::RemoveHasChildren);
+        let descendant_ids = self.descendant_ids(&full_id);
+        if !descendant_ids.is_empty() && !force {
+            return Err(TaskListError::RemoveHasChildren);
+        }
+
+        // Remove sub-tasks before their parents
+        for id in descendant_ids.iter().rev() {
+            self.unlink_task(id);
+
+            if !self.quiet {
+                println!("removed task {} ({})", id, id);
             }
         }
 
-        self.tasks.retain(|task| *task.id() != full_id);
-        self.compute_prefixes();
+        self.unlink_task(&full_id);
 
-        println!("removed task {} ({})", prefix, full_id);
+        if !self.quiet {
+            println!("removed task {} ({})", prefix, full_id);
+        }
 
         Ok(())
     }
@@ -244,120 +1306,504 @@ impl TaskList {
     pub fn complete_task(&mut self, prefix: &str, force: bool) -> Result<(), TaskListError> {
         let full_id = self.get_full_id(prefix)?;
 
-        let children = self.get_children_tasks(&full_id)?;
+        if !self.all_descendants_completed(&full_id) {
+            if !force {
+                return Err(TaskListError::CompleteHasChildren);
+            }
 
-        let all_descendants_completed = self.all_descendants_completed(prefix);
+            // Complete sub-tasks before their parents
+            for id in self.descendant_ids(&full_id).iter().rev() {
+                let task = self.get_task(id)?;
+                if !task.is_completed() {
+                    task.set_complete(true);
 
-        if !all_descendants_completed {
-            let children_ids: Vec<String> =
-                children.into_iter().map(|c| c.id().to_string()).collect();
-            if !children_ids.is_empty() {
-                if force {
-                    for id in &children_ids {
-                        self.complete_task(id, force)?;
+                    if !self.quiet {
+                        println!("completed task {} ({})", id, id);
                     }
-                } else {
-                    return Err(TaskListError::CompleteHasChildren);
                 }
             }
         }
 
-        let task = match self.get_task(prefix) {
-            Err(e) => {
-                return Err(e);
-            }
-            Ok(task) => task,
-        };
+        let task = self.get_task(&full_id)?;
 
         task.set_complete(true);
-        self.compute_prefixes();
 
-        println!("completed task {} ({})", prefix, full_id);
+        if !self.quiet {
+            println!("completed task {} ({})", prefix, full_id);
+        }
 
         Ok(())
     }
 
-    fn get_full_id(&self, prefix: &str) -> Result<String, TaskListError> {
-        let mut full_id = None;
-        for task in &self.tasks {
-            if task.id() == prefix {
-                full_id = Some(task.id().to_string());
-                break;
-            } else if task.id().starts_with(prefix) {
-                if full_id.is_some() {
-                    // more than one task matches this prefix
-                    return Err(TaskListError::AmbiguousPrefix);
+    pub fn get_full_id(&self, prefix: &str) -> Result<String, TaskListError> {
+        match self.prefixes.lookup(prefix) {
+            Lookup::Unique(full_id) => Ok(full_id.to_string()),
+            Lookup::Ambiguous(ids) => {
+                let mut candidates: Vec<String> = ids
+                    .iter()
+                    .take(MAX_CANDIDATES)
+                    .filter_map(|id| self.task_by_id(id))
+                    .map(|task| {
+                        let prefix = self.prefixes.prefix(task.id()).unwrap_or(task.id());
+                        format!("{}: {}", prefix, task.desc())
+                    })
+                    .collect();
+                if ids.len() > MAX_CANDIDATES {
+                    candidates.push(format!("... and {} more", ids.len() - MAX_CANDIDATES));
                 }
-
-                full_id = Some(task.id().to_string());
+                Err(TaskListError::AmbiguousPrefix(prefix.to_string(), candidates))
             }
+            Lookup::NotFound => Err(TaskListError::BadPrefix),
         }
+    }
+
+    pub fn get_task(&mut self, prefix: &str) -> Result<&mut task::Task, TaskListError> {
+        let full_id = self.get_full_id(prefix)?;
 
-        match full_id {
-            Some(full_id) => Ok(full_id),
+        // The caller may modify the task
+        self.changed.insert(full_id.to_string());
+
+        match self.index.get(&full_id) {
+            Some(pos) => Ok(&mut self.tasks[*pos]),
             None => Err(TaskListError::BadPrefix),
         }
     }
 
-    pub fn get_task(&mut self, prefix: &str) -> Result<&mut task::Task, TaskListError> {
+    pub fn get_task_ref(&self, prefix: &str) -> Result<&task::Task, TaskListError> {
         let full_id = self.get_full_id(prefix)?;
 
-        for task in &mut self.tasks {
-            if task.id().eq(&full_id) {
-                return Ok(task);
+        self.task_by_id(&full_id).ok_or(TaskListError::BadPrefix)
+    }
+
+    pub fn all_descendants_completed(&self, prefix: &str) -> bool {
+        match self.get_full_id(prefix) {
+            Ok(full_id) => self
+                .descendant_ids(&full_id)
+                .iter()
+                .all(|id| self.task_by_id(id).is_some_and(|task| task.is_completed())),
+            Err(_) => true,
+        }
+    }
+
+    // The task with exactly this id, unlike `get_task_ref` which takes a prefix.
+    pub fn task_by_id(&self, id: &str) -> Option<&task::Task> {
+        self.index.get(id).map(|pos| &self.tasks[*pos])
+    }
+
+    fn child_ids(&self, parent_id: Option<&str>) -> &[String] {
+        let children = match parent_id {
+            Some(parent_id) => self.children.get(parent_id),
+            None => Some(&self.roots),
+        };
+
+        children.map_or(&[], |children| children.as_slice())
+    }
+
+    // Ids of all descendants of a task, parents before their sub-tasks.
+    pub fn descendant_ids(&self, id: &str) -> Vec<String> {
+        let mut ids = Vec::new();
+        let mut seen = HashSet::from([id]);
+        let mut stack: Vec<&String> = self.child_ids(Some(id)).iter().rev().collect();
+
+        while let Some(id) = stack.pop() {
+            // Parents can form a cycle in a hand edited file
+            if !seen.insert(id) {
+                continue;
             }
+            ids.push(id.to_string());
+            stack.extend(self.child_ids(Some(id)).iter().rev());
         }
 
-        Err(TaskListError::BadPrefix)
+        ids
     }
 
-    pub fn get_children_tasks(&self, prefix: &str) -> Result<Vec<&task::Task>, TaskListError> {
-        let mut children = Vec::new();
-        let full_id = self.get_full_id(prefix)?;
+    // Record that task `id` was removed from `sources[source]`, if that file is synced.
+    fn add_tombstone(&mut self, source: usize, id: &str, timestamp: f64) {
+        let header = &mut self.sources[source].header;
+        if header.synced {
+            header.tombstones.insert(id.to_string(), timestamp);
+        }
+    }
 
-        for task in &self.tasks {
-            if task.parent_id().eq(&Some(full_id.to_string())) {
-                children.push(task);
+    // Index in `sources` of the file holding task `id`, the main file for new top level tasks.
+    fn owner_of(&self, id: Option<&str>) -> usize {
+        id.and_then(|id| self.owners.get(id)).cloned().unwrap_or(0)
+    }
+
+    // Store task `id` in `sources[source]`, removing it from the file it was in.
+    fn set_owner(&mut self, id: &str, source: usize) {
+        if let Some(old_source) = self.owners.insert(id.to_string(), source) {
+            if old_source != source {
+                self.add_tombstone(old_source, id, task::now());
+                self.removed.insert((old_source, id.to_string()));
             }
         }
 
-        Ok(children)
+        self.sources[source].header.tombstones.remove(id);
+        self.removed.remove(&(source, id.to_string()));
+        self.changed.insert(id.to_string());
     }
 
-    pub fn all_descendants_completed(&self, prefix: &str) -> bool {
-        let mut all_descendants_completed = true;
+    // Add the task at `pos` to the id and parent indexes.
+    fn index_task(&mut self, pos: usize) {
+        let task = &self.tasks[pos];
+        // Of several tasks with the same id in one file only the first is shown, all are saved
+        if self.index.contains_key(task.id()) {
+            return;
+        }
+        self.index.insert(task.id().to_string(), pos);
 
-        if let Ok(children) = self.get_children_tasks(prefix) {
-            for child in children {
-                if !child.is_completed() {
-                    all_descendants_completed = false;
-                    break;
-                }
+        match task.parent_id() {
+            Some(parent_id) => {
+                self.children.entry(parent_id.to_string()).or_default().push(task.id().to_string())
+            }
+            None => self.roots.push(task.id().to_string()),
+        }
+    }
 
-                if !self.all_descendants_completed(child.id()) {
-                    all_descendants_completed = false;
-                    break;
+    fn unlink_from_parent(&mut self, id: &str, parent_id: Option<&str>) {
+        match parent_id {
+            Some(parent_id) => {
+                if let Some(siblings) = self.children.get_mut(parent_id) {
+                    siblings.retain(|sibling| sibling != id);
+                    if siblings.is_empty() {
+                        self.children.remove(parent_id);
+                    }
                 }
             }
+            None => self.roots.retain(|root| root != id),
         }
+    }
+
+    // Remove a single task and keep the indexes up to date.  Its sub-tasks are left alone.
+    fn unlink_task(&mut self, id: &str) {
+        let pos = match self.index.remove(id) {
+            Some(pos) => pos,
+            None => return,
+        };
+
+        let task = self.tasks.swap_remove(pos);
+        if let Some(moved) = self.tasks.get(pos) {
+            self.index.insert(moved.id().to_string(), pos);
+        }
+
+        self.unlink_from_parent(id, task.parent_id().as_deref());
+        self.prefixes.remove(id);
+
+        let source = self.owners.remove(id).unwrap_or(0);
+        self.add_tombstone(source, id, task::now());
+        self.changed.remove(id);
+        self.removed.insert((source, id.to_string()));
+    }
+}
+
+// Load a task list using the named storage backend, or one picked from the file extension.
+pub fn create_from_file(file: &str, backend: Option<&str>) -> Result<TaskList, TaskListError> {
+    load(&[file], backend, false)
+}
+
+// Load the tasks of several files, and of any files they include, into one task list.
+pub fn create_from_files(files: &[&str], backend: Option<&str>) -> Result<TaskList, TaskListError> {
+    load(files, backend, true)
+}
 
-        all_descendants_completed
+// Path of a file included by `file`, relative to the directory `file` is in.
+fn include_path(file: &str, include: &str) -> String {
+    match Path::new(file).parent() {
+        Some(dir) => dir.join(include).to_string_lossy().to_string(),
+        None => include.to_string(),
     }
 }
 
-pub fn create_from_file(file: &str) -> TaskList {
+fn load(files: &[&str], backend: Option<&str>, includes: bool) -> Result<TaskList, TaskListError> {
+    let mut sources = Vec::new();
+    let mut owners = HashMap::new();
     let mut tasks = Vec::new();
 
-    if let Ok(lines) = read_lines(file) {
-        for task_string in lines.into_iter().flatten() {
-            let task = task::create_from_file_string(&task_string);
-            tasks.push(task);
+    // Files given on the command line use `backend`, included files are picked by extension
+    let mut pending: VecDeque<(String, Option<&str>)> =
+        files.iter().map(|file| (file.to_string(), backend)).collect();
+    let mut loaded = HashSet::new();
+    while let Some((file, backend)) = pending.pop_front() {
+        // Load each file once, however it is named
+        if !loaded.insert(fs::canonicalize(&file).unwrap_or_else(|_| file.clone().into())) {
+            continue;
+        }
+
+        let mut storage = storage::open(&file, backend)?;
+        let (header, file_tasks) = storage.load()?;
+
+        if includes {
+            for include in &header.includes {
+                pending.push_back((include_path(&file, include), None));
+            }
+        }
+
+        // A task may only be in one file, duplicates within a file load as they always have
+        for task in file_tasks {
+            match owners.insert(task.id().to_string(), sources.len()) {
+                Some(owner) if owner != sources.len() => {
+                    return Err(TaskListError::DuplicateTaskInFiles(task.id().to_string()))
+                }
+                _ => tasks.push(task),
+            }
         }
+
+        sources.push(Source { file, storage: Arc::new(Mutex::new(storage)), header });
+    }
+
+    let mut indexed = HashSet::new();
+    let prefixes = PrefixIndex::new(
+        tasks
+            .iter()
+            .filter(|t| indexed.insert(t.id().as_str()))
+            .map(|t| (t.id().as_str(), t.show_full_id())),
+    );
+
+    let mut task_list = TaskList {
+        sources,
+        owners,
+        tasks,
+        index: HashMap::new(),
+        children: HashMap::new(),
+        roots: Vec::new(),
+        prefixes,
+        changed: HashSet::new(),
+        removed: HashSet::new(),
+        changed_headers: HashSet::new(),
+        stable_prefixes: false,
+        hooks: None,
+        quiet: false,
+        ascii: false,
+        width: None,
+        template: Template::default(),
+        tag_colors: HashMap::new(),
+    };
+
+    for pos in 0..task_list.tasks.len() {
+        task_list.index_task(pos);
+    }
+
+    Ok(task_list)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::temp_file;
+    use std::fs;
+
+    fn task_list(file: &str) -> TaskList {
+        let mut tasks = create_from_file(file, Some("text")).unwrap();
+        tasks.set_quiet(true);
+        tasks
     }
 
-    let mut task_list =
-        TaskList { file: file.to_string(), tasks, prefixes: HashMap::new(), prefix_max_len: 64 };
+    #[test]
+    fn removals_are_only_recorded_in_synced_files() {
+        let mut tasks = task_list(&temp_file("unsynced.t"));
+        tasks.add_task(None, Some("a"), "A").unwrap();
+        tasks.remove_task("a", false).unwrap();
+        assert!(tasks.tombstones().is_empty());
 
-    task_list.compute_prefixes();
-    task_list
+        tasks.set_synced();
+        tasks.add_task(None, Some("b"), "B").unwrap();
+        tasks.remove_task("b", false).unwrap();
+        assert_eq!(tasks.tombstones().keys().collect::<Vec<_>>(), ["b"]);
+    }
+
+    #[test]
+    fn old_tombstones_are_dropped_on_save() {
+        let file = temp_file("tombstones.t");
+        let mut tasks = task_list(&file);
+        tasks.set_synced();
+        let day = 24.0 * 60.0 * 60.0;
+        tasks.delete_task("old", task::now() - (TOMBSTONE_DAYS + 1.0) * day);
+        tasks.delete_task("recent", task::now() - day);
+        tasks.save().unwrap();
+
+        let tasks = task_list(&file);
+        assert!(tasks.synced());
+        assert_eq!(tasks.tombstones().keys().collect::<Vec<_>>(), ["recent"]);
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn a_task_may_only_be_in_one_file() {
+        let (first, second) = (temp_file("first.t"), temp_file("second.t"));
+        let line = "Task | {\"id\":\"abc\",\"timestamp\":1.0}\n";
+        fs::write(&first, line.repeat(2)).unwrap();
+        fs::write(&second, line).unwrap();
+
+        // Duplicates within a file load, the first one is shown
+        assert_eq!(create_from_files(&[&first], None).unwrap().get_full_id("a").unwrap(), "abc");
+        assert!(matches!(
+            create_from_files(&[&first, &second], None),
+            Err(TaskListError::DuplicateTaskInFiles(id)) if id == "abc"
+        ));
+        fs::remove_file(first).unwrap();
+        fs::remove_file(second).unwrap();
+    }
+
+    #[test]
+    fn custom_ids_must_be_safe_to_type() {
+        let tasks = task_list(&temp_file("bad-ids.t"));
+        for id in ["", "-x", "a b", "a|b", "#1", "ü"] {
+            assert!(
+                matches!(tasks.check_custom_id(id, None), Err(TaskListError::BadId(_))),
+                "{}",
+                id
+            );
+        }
+        for id in ["a", "A_1", "v1.2-rc"] {
+            assert!(tasks.check_custom_id(id, None).is_ok(), "{}", id);
+        }
+    }
+
+    #[test]
+    fn custom_ids_must_not_be_prefixes_of_each_other() {
+        let mut tasks = task_list(&temp_file("prefix-ids.t"));
+        tasks.add_task(None, Some("abc"), "Custom").unwrap();
+
+        assert!(matches!(tasks.check_custom_id("abc", None), Err(TaskListError::DuplicateTask)));
+        assert!(matches!(tasks.check_custom_id("ab", None), Err(TaskListError::IdCollision(..))));
+        assert!(matches!(tasks.check_custom_id("abcd", None), Err(TaskListError::IdCollision(..))));
+        assert!(tasks.check_custom_id("abd", None).is_ok());
+        // A task may be renamed to an id starting with its own
+        assert!(tasks.check_custom_id("abcd", Some("abc")).is_ok());
+    }
+
+    #[test]
+    fn custom_ids_must_not_be_prefixes_of_hashes() {
+        let mut tasks = task_list(&temp_file("hash-ids.t"));
+        let id = tasks.add_task(None, None, "Hashed").unwrap();
+
+        let error = tasks.check_custom_id(&id[..3], None);
+        assert!(matches!(error, Err(TaskListError::IdCollision(ref new, _)) if *new == id[..3]));
+        assert!(tasks.add_task(None, Some(&id[..3]), "Custom").is_err());
+    }
+
+    #[test]
+    fn renamed_tasks_lose_their_stable_prefixes() {
+        let file = temp_file("rename.t");
+        let mut tasks = task_list(&file);
+        tasks.set_stable_prefixes();
+        let id = tasks.add_task(None, None, "Hello").unwrap();
+        let prefix = tasks.prefix(&id).unwrap().to_string();
+        tasks.save().unwrap();
+
+        let mut tasks = task_list(&file);
+        tasks.set_stable_prefixes();
+        // Hashes are hexadecimal, so no prefix of the old id finds the new one
+        tasks.rename_task(&prefix, "x1").unwrap();
+        tasks.save().unwrap();
+
+        let mut tasks = task_list(&file);
+        tasks.set_stable_prefixes();
+        assert!(tasks.task_by_id("x1").unwrap().prefixes().is_empty());
+        assert_eq!(tasks.prefix("x1").unwrap(), "x1");
+        assert_eq!(tasks.get_full_id("x1").unwrap(), "x1");
+        assert!(tasks.get_full_id(&prefix).is_err());
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn copies_keep_structure_and_completion_unless_reset() {
+        let mut tasks = task_list(&temp_file("copies.t"));
+        let a = tasks.add_task(None, None, "A").unwrap();
+        let b = tasks.add_task(Some(&a), None, "B").unwrap();
+        tasks.get_task(&b).unwrap().set_tags(vec!["x".to_string()]);
+        tasks.complete_task(&b, false).unwrap();
+        let target = tasks.add_task(None, None, "Target").unwrap();
+
+        for reset in [false, true] {
+            let subtree = tasks.subtree(&a).unwrap();
+            let copies = tasks.add_copies(&subtree, Some(&target), reset).unwrap();
+            assert_eq!(copies.len(), 2);
+            assert!(!copies.contains(&a) && !copies.contains(&b));
+
+            let copy_a = tasks.get_task_ref(&copies[0]).unwrap();
+            assert_eq!(copy_a.desc(), "A");
+            assert_eq!(copy_a.parent_id().as_deref(), Some(target.as_str()));
+            let copy_b = tasks.get_task_ref(&copies[1]).unwrap();
+            assert_eq!(copy_b.desc(), "B");
+            assert_eq!(copy_b.parent_id().as_deref(), Some(copies[0].as_str()));
+            assert_eq!(copy_b.tags(), &["x"]);
+            assert_eq!(copy_b.is_completed(), !reset);
+        }
+        assert!(tasks.get_task_ref(&b).unwrap().is_completed());
+    }
+
+    #[test]
+    fn parent_cycles_in_a_hand_edited_file_are_walked_once() {
+        let file = temp_file("cycle.t");
+        let lines = [
+            r#"A | {"id":"a","parent_id":"c","timestamp":1.0,"completed_timestamp":2.0}"#,
+            r#"B | {"id":"b","parent_id":"a","timestamp":1.0,"completed_timestamp":2.0}"#,
+            r#"C | {"id":"c","parent_id":"b","timestamp":1.0,"completed_timestamp":2.0}"#,
+        ];
+        fs::write(&file, lines.join("\n") + "\n").unwrap();
+        let tasks = task_list(&file);
+
+        assert_eq!(tasks.descendant_ids("a"), ["b", "c"]);
+        let subtree: Vec<String> =
+            tasks.subtree("a").unwrap().iter().map(|task| task.id().to_string()).collect();
+        assert_eq!(subtree, ["a", "b", "c"]);
+        let ancestors: Vec<&str> = tasks
+            .ancestors(tasks.task_by_id("a").unwrap())
+            .iter()
+            .map(|task| task.id().as_str())
+            .collect();
+        assert_eq!(ancestors, ["b", "c"]);
+        assert!(tasks.completed_subtrees().is_empty());
+
+        let none = HashSet::new();
+        let mut visit =
+            Visit { hidden: &none, collapsed: &none, include: None, seen: none.clone() };
+        let mut rows = Vec::new();
+        tasks.tree_rows_for(&["a".to_string()], "│", None, &mut visit, &mut rows);
+        assert_eq!(rows.iter().map(|row| row.id.as_str()).collect::<Vec<_>>(), ["a", "b", "c"]);
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn exit_codes_are_unique_and_listed_in_order() {
+        let s = String::new;
+        let errors = [
+            TaskListError::AmbiguousPrefix(s(), Vec::new()),
+            TaskListError::BadPrefix,
+            TaskListError::BadParentPrefix,
+            TaskListError::MoveIntoDescendant,
+            TaskListError::DuplicateTask,
+            TaskListError::DuplicateTaskInFiles(s()),
+            TaskListError::RemoveHasChildren,
+            TaskListError::CompleteHasChildren,
+            TaskListError::BadFilter(s()),
+            TaskListError::MissingTags,
+            TaskListError::BadDate(s()),
+            TaskListError::NestedShell(s()),
+            TaskListError::UnsupportedBackend(s()),
+            TaskListError::StorageError(s()),
+            TaskListError::BadConfig(s()),
+            TaskListError::BadId(s()),
+            TaskListError::IdCollision(s(), s()),
+            TaskListError::BadTemplateName(s()),
+            TaskListError::NoSuchTemplate(s()),
+            TaskListError::HookFailed(s(), s()),
+            TaskListError::IOError(std::io::Error::other("")),
+        ];
+        let mut codes: Vec<i32> = errors.iter().map(TaskListError::exit_code).collect();
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), errors.len());
+
+        let listed: Vec<i32> = EXIT_STATUS
+            .lines()
+            .filter_map(|line| line.split_whitespace().next()?.parse().ok())
+            .collect();
+        assert!(listed.windows(2).all(|pair| pair[0] < pair[1]));
+        for code in codes {
+            assert!(code > 2 && listed.contains(&code), "{}", code);
+        }
+    }
 }
@@ -0,0 +1,151 @@
+use super::config;
+use super::storage::{self, Header};
+use super::task;
+use super::task_list::{self, TaskList, TaskListError};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+// Named templates are tasks and their sub-tasks saved for adding again later, e.g. a release
+// checklist.  Each is a text task file in `.t/templates` next to the task file.
+fn path(tasks: &TaskList, name: &str) -> Result<PathBuf, TaskListError> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || "_.-".contains(c);
+    if name.is_empty() || name.starts_with(['.', '-']) || !name.chars().all(valid) {
+        return Err(TaskListError::BadTemplateName(name.to_string()));
+    }
+    Ok(config::dir(tasks.file()).join("templates").join(name))
+}
+
+// Save the task `prefix` and its sub-tasks as template `name`, replacing any template with that
+// name.  Returns the number of tasks saved.
+pub fn save(tasks: &TaskList, prefix: &str, name: &str) -> Result<usize, TaskListError> {
+    let path = path(tasks, name)?;
+    let mut subtree = tasks.subtree(prefix)?;
+    subtree[0].set_parent_id(None);
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let subtree: Vec<&task::Task> = subtree.iter().collect();
+    storage::open(&path.to_string_lossy(), Some("text"))?.save(&Header::default(), &subtree)?;
+
+    Ok(subtree.len())
+}
+
+// The tasks of template `name`, as returned by `TaskList::subtree`.
+pub fn load(tasks: &TaskList, name: &str) -> Result<Vec<task::Task>, TaskListError> {
+    let path = path(tasks, name)?;
+    if !path.is_file() {
+        return Err(TaskListError::NoSuchTemplate(name.to_string()));
+    }
+    let template = task_list::create_from_file(&path.to_string_lossy(), Some("text"))?;
+
+    let mut roots: Vec<&task::Task> =
+        template.tasks().iter().filter(|task| task.parent_id().is_none()).collect();
+    roots.sort_by(|a, b| a.timestamp().partial_cmp(&b.timestamp()).unwrap());
+
+    let mut subtrees = Vec::new();
+    for root in roots {
+        subtrees.extend(template.subtree(root.id())?);
+    }
+    Ok(subtrees)
+}
+
+// Names of the saved templates, sorted.
+pub fn names(tasks: &TaskList) -> Result<Vec<String>, TaskListError> {
+    let entries = match fs::read_dir(config::dir(tasks.file()).join("templates")) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut names = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            names.push(entry.file_name().to_string_lossy().to_string());
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::temp_file;
+
+    // A task list in a directory of its own, so its templates are too.
+    fn task_list(name: &str) -> TaskList {
+        let dir = temp_file(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let file = PathBuf::from(dir).join("tasks.t");
+        let mut tasks = task_list::create_from_file(&file.to_string_lossy(), Some("text")).unwrap();
+        tasks.set_quiet(true);
+        tasks
+    }
+
+    // The description of each task with the description of its parent.
+    fn structure(tasks: &[task::Task]) -> Vec<(String, Option<String>)> {
+        let desc = |id: &str| tasks.iter().find(|task| task.id() == id).unwrap().desc().clone();
+        let mut structure: Vec<_> = tasks
+            .iter()
+            .map(|task| (task.desc().clone(), task.parent_id().as_deref().map(desc)))
+            .collect();
+        structure.sort();
+        structure
+    }
+
+    #[test]
+    fn templates_round_trip() {
+        let mut tasks = task_list("templates-round-trip");
+        let release = tasks.add_task(None, None, "Release").unwrap();
+        let tag = tasks.add_task(Some(&release), None, "Tag").unwrap();
+        let publish = tasks.add_task(Some(&release), None, "Publish").unwrap();
+        tasks.add_task(Some(&publish), None, "Announce").unwrap();
+        tasks.get_task(&tag).unwrap().set_tags(vec!["git".to_string()]);
+        tasks.complete_task(&tag, false).unwrap();
+
+        assert_eq!(save(&tasks, &release, "release").unwrap(), 4);
+        assert_eq!(names(&tasks).unwrap(), ["release"]);
+
+        let template = load(&tasks, "release").unwrap();
+        assert_eq!(template[0].desc(), "Release");
+        assert!(template[0].parent_id().is_none());
+        assert_eq!(structure(&template), structure(&tasks.subtree(&release).unwrap()));
+        let tag = template.iter().find(|task| task.desc() == "Tag").unwrap();
+        assert_eq!(tag.tags(), &["git"]);
+        assert!(tag.is_completed());
+
+        fs::remove_dir_all(config::dir(tasks.file()).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn saving_replaces_a_template() {
+        let mut tasks = task_list("templates-replace");
+        let a = tasks.add_task(None, None, "A").unwrap();
+        tasks.add_task(Some(&a), None, "A1").unwrap();
+        let b = tasks.add_task(None, None, "B").unwrap();
+        save(&tasks, &a, "t").unwrap();
+        save(&tasks, &b, "t").unwrap();
+
+        let template = load(&tasks, "t").unwrap();
+        assert_eq!(template.len(), 1);
+        assert_eq!(template[0].desc(), "B");
+
+        fs::remove_dir_all(config::dir(tasks.file()).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn bad_and_missing_templates() {
+        let tasks = task_list("templates-missing");
+        assert!(names(&tasks).unwrap().is_empty());
+        for name in ["", ".hidden", "-x", "a/b", "a b"] {
+            assert!(matches!(load(&tasks, name), Err(TaskListError::BadTemplateName(_))));
+        }
+        assert!(matches!(load(&tasks, "nope"), Err(TaskListError::NoSuchTemplate(_))));
+
+        fs::remove_dir_all(config::dir(tasks.file()).parent().unwrap()).unwrap();
+    }
+}
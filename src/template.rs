@@ -0,0 +1,207 @@
+use super::task_list::TaskListError;
+
+// How rows were drawn before templates could be set
+pub const DEFAULT: &str = "{indent} {checkmark} {prefix}: {tags}{desc}";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Field {
+    Indent,
+    Checkmark,
+    Prefix,
+    Id,
+    Tags,
+    Desc,
+    Due,
+    Priority,
+    Age,
+    Status,
+    Progress,
+}
+
+const FIELDS: &[(&str, Field)] = &[
+    ("indent", Field::Indent),
+    ("checkmark", Field::Checkmark),
+    ("prefix", Field::Prefix),
+    ("id", Field::Id),
+    ("tags", Field::Tags),
+    ("desc", Field::Desc),
+    ("due", Field::Due),
+    ("priority", Field::Priority),
+    ("age", Field::Age),
+    ("status", Field::Status),
+    ("progress", Field::Progress),
+];
+
+#[derive(Clone)]
+enum Segment {
+    Text(String),
+    Field(Field),
+}
+
+// The format of a row, e.g. "{indent} {checkmark} {prefix}: {tags}{desc}".  The lead of a row is
+// its text up to the first field other than the indent and checkmark, wrapped lines start under
+// the end of it.
+#[derive(Clone)]
+pub struct Template {
+    lead: Vec<Segment>,
+    body: Vec<Segment>,
+}
+
+impl Default for Template {
+    fn default() -> Self {
+        Template::parse(DEFAULT).unwrap()
+    }
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Template, TaskListError> {
+        let mut segments = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => {
+                    return Err(TaskListError::BadConfig(format!(
+                        "unclosed '{{' in template '{}'",
+                        template
+                    )))
+                }
+            };
+
+            let name = &rest[start + 1..end];
+            let field = match FIELDS.iter().find(|(field_name, _)| *field_name == name) {
+                Some((_, field)) => *field,
+                None => {
+                    return Err(TaskListError::BadConfig(format!(
+                        "unknown placeholder '{{{}}}' in template",
+                        name
+                    )))
+                }
+            };
+
+            if start > 0 {
+                segments.push(Segment::Text(rest[..start].to_string()));
+            }
+            segments.push(Segment::Field(field));
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Text(rest.to_string()));
+        }
+
+        let split = segments
+            .iter()
+            .position(|segment| {
+                matches!(segment, Segment::Field(field)
+                    if *field != Field::Indent && *field != Field::Checkmark)
+            })
+            .unwrap_or(segments.len());
+        let body = segments.split_off(split);
+
+        Ok(Template { lead: segments, body })
+    }
+
+    pub fn has(&self, field: Field) -> bool {
+        self.lead.iter().chain(self.body.iter()).any(
+            |segment| matches!(segment, Segment::Field(segment_field) if *segment_field == field),
+        )
+    }
+
+    pub fn lead(&self, value: impl Fn(Field) -> String) -> String {
+        render(&self.lead, value, false)
+    }
+
+    // The lead of a wrapped line: the values of the fields, with spaces in place of any text.
+    pub fn guide(&self, value: impl Fn(Field) -> String) -> String {
+        render(&self.lead, value, true)
+    }
+
+    pub fn body(&self, value: impl Fn(Field) -> String) -> String {
+        render(&self.body, value, false)
+    }
+}
+
+fn render(segments: &[Segment], value: impl Fn(Field) -> String, blank_text: bool) -> String {
+    let mut text = String::new();
+    for segment in segments {
+        match segment {
+            Segment::Text(segment) if blank_text => text += &" ".repeat(visible_width(segment)),
+            Segment::Text(segment) => text += segment,
+            Segment::Field(field) => text += &value(*field),
+        }
+    }
+    text
+}
+
+// Number of columns `text` takes up on a terminal, leaving out color escape sequences.
+pub fn visible_width(text: &str) -> usize {
+    let mut width = 0;
+    let mut escape = false;
+    for c in text.chars() {
+        if escape {
+            escape = !c.is_ascii_alphabetic();
+        } else if c == '\x1b' {
+            escape = true;
+        } else {
+            width += 1;
+        }
+    }
+    width
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(field: Field) -> String {
+        match field {
+            Field::Indent => "  ".to_string(),
+            Field::Checkmark => "[x]".to_string(),
+            Field::Prefix => "ab".to_string(),
+            Field::Desc => "Write tests".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    #[test]
+    fn lead_ends_before_the_first_other_field() {
+        let template = Template::default();
+        assert_eq!(template.lead(value), "   [x] ");
+        assert_eq!(template.guide(value), "   [x] ");
+        assert_eq!(template.body(value), "ab: Write tests");
+    }
+
+    #[test]
+    fn guide_blanks_text() {
+        let template = Template::parse("> {checkmark} {desc}").unwrap();
+        assert_eq!(template.lead(value), "> [x] ");
+        assert_eq!(template.guide(value), "  [x] ");
+        assert_eq!(template.body(value), "Write tests");
+    }
+
+    #[test]
+    fn has() {
+        let template = Template::parse("{prefix} {due} {desc}").unwrap();
+        assert!(template.has(Field::Due));
+        assert!(!template.has(Field::Priority));
+    }
+
+    #[test]
+    fn text_without_fields() {
+        let template = Template::parse("just text").unwrap();
+        assert_eq!(template.lead(value), "just text");
+        assert_eq!(template.body(value), "");
+    }
+
+    #[test]
+    fn bad_templates_are_rejected() {
+        assert!(matches!(Template::parse("{desc"), Err(TaskListError::BadConfig(_))));
+        assert!(matches!(Template::parse("{owner} {desc}"), Err(TaskListError::BadConfig(_))));
+    }
+
+    #[test]
+    fn visible_width_skips_escape_sequences() {
+        assert_eq!(visible_width("abc"), 3);
+        assert_eq!(visible_width("\x1b[1;33mab\x1b[0m: c"), 5);
+    }
+}
@@ -0,0 +1,446 @@
+use super::task_list::{TaskList, TreeRow};
+use crossterm::cursor;
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::style::{Attribute, Print, SetAttribute};
+use crossterm::terminal::{self, ClearType};
+use crossterm::{execute, queue};
+use std::collections::HashSet;
+use std::io::{self, Write};
+
+const HELP: &str = "j/k move  h/l fold  space done  a child  o sibling  e edit  t tag  m move  \
+                    / filter  H hide done  q quit";
+
+enum Prompt {
+    AddChild,
+    AddSibling,
+    Edit,
+    Tag,
+    Filter,
+}
+
+enum Mode {
+    Normal,
+    Input(Prompt, String),
+    // Id of the task being moved
+    Move(String),
+}
+
+// Restores the terminal when the ui exits, even on error.
+struct TerminalGuard;
+
+impl TerminalGuard {
+    fn new() -> io::Result<TerminalGuard> {
+        terminal::enable_raw_mode()?;
+        execute!(
+            io::stdout(),
+            terminal::EnterAlternateScreen,
+            terminal::DisableLineWrap,
+            cursor::Hide
+        )?;
+        Ok(TerminalGuard)
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let _ = execute!(
+            io::stdout(),
+            cursor::Show,
+            terminal::EnableLineWrap,
+            terminal::LeaveAlternateScreen
+        );
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+struct Ui<'a> {
+    tasks: &'a mut TaskList,
+    hide_completed: bool,
+    collapsed: HashSet<String>,
+    filter: String,
+    rows: Vec<TreeRow>,
+    cursor: usize,
+    offset: usize,
+    mode: Mode,
+    message: String,
+    quit: bool,
+}
+
+pub fn run(tasks: &mut TaskList, hide_completed: bool) -> io::Result<()> {
+    let _guard = TerminalGuard::new()?;
+
+    // Start with the tasks folded with `t fold` collapsed
+    let collapsed = tasks.collapsed_ids();
+    let mut ui = Ui {
+        tasks,
+        hide_completed,
+        collapsed,
+        filter: String::new(),
+        rows: Vec::new(),
+        cursor: 0,
+        offset: 0,
+        mode: Mode::Normal,
+        message: String::new(),
+        quit: false,
+    };
+    ui.refresh();
+
+    while !ui.quit {
+        ui.draw()?;
+        if let Event::Key(key) = event::read()? {
+            if key.kind != KeyEventKind::Release {
+                ui.handle_key(key);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+impl<'a> Ui<'a> {
+    fn selected_id(&self) -> Option<String> {
+        self.rows.get(self.cursor).map(|row| row.id.to_string())
+    }
+
+    fn select(&mut self, id: &str) {
+        if let Some(pos) = self.rows.iter().position(|row| row.id == id) {
+            self.cursor = pos;
+        }
+    }
+
+    // Rebuild the visible rows after the task list, folding or filter changed.
+    fn refresh(&mut self) {
+        let selected = self.selected_id();
+
+        if self.filter.is_empty() {
+            self.rows = self.tasks.tree_rows(self.hide_completed, &self.collapsed, None);
+        } else {
+            // Keep the ancestors of every matching task so matches are shown in context.
+            let filter = self.filter.to_lowercase();
+            let mut matches = Vec::new();
+            for task in self.tasks.tasks() {
+                let prefix = self.tasks.prefix(task.id()).map_or("", |p| p.as_str());
+                if task.desc().to_lowercase().contains(&filter)
+                    || prefix.starts_with(&filter)
+                    || task.tags().iter().any(|tag| tag.to_lowercase().contains(&filter))
+                {
+                    matches.push(task.id().to_string());
+                }
+            }
+            let include = self.tasks.with_ancestors(&matches);
+            self.rows = self.tasks.tree_rows(self.hide_completed, &HashSet::new(), Some(&include));
+        }
+
+        if let Some(id) = selected {
+            self.select(&id);
+        }
+        if self.cursor >= self.rows.len() {
+            self.cursor = self.rows.len().saturating_sub(1);
+        }
+    }
+
+    fn save(&mut self) {
+        if let Err(e) = self.tasks.save() {
+            self.message = format!("Error: {}", e);
+        }
+        self.refresh();
+    }
+
+    fn draw(&mut self) -> io::Result<()> {
+        let (width, height) = terminal::size()?;
+        // One line for the header and one for the status line
+        let list_height = (height as usize).saturating_sub(2).max(1);
+
+        if self.cursor < self.offset {
+            self.offset = self.cursor;
+        } else if self.cursor >= self.offset + list_height {
+            self.offset = self.cursor + 1 - list_height;
+        }
+
+        let mut stdout = io::stdout();
+        queue!(stdout, terminal::Clear(ClearType::All), cursor::MoveTo(0, 0))?;
+
+        let header = if self.filter.is_empty() {
+            format!("Tasks: {}", self.tasks.tasks().len())
+        } else {
+            format!("Tasks: {} shown, filter: {}", self.rows.len(), self.filter)
+        };
+        queue!(stdout, Print(header))?;
+
+        for (line, row) in self.rows.iter().enumerate().skip(self.offset).take(list_height) {
+            let task = match self.tasks.get_task_ref(&row.id) {
+                Ok(task) => task,
+                Err(_) => continue,
+            };
+
+            queue!(stdout, cursor::MoveTo(0, (line - self.offset + 1) as u16))?;
+            if line == self.cursor {
+                // Draw the selected row without colors so the highlight is not interrupted.
+                let colorize = colored::control::SHOULD_COLORIZE.should_colorize();
+                colored::control::set_override(false);
+                let text = self.tasks.format_row(row, task);
+                colored::control::set_override(colorize);
+                queue!(
+                    stdout,
+                    SetAttribute(Attribute::Reverse),
+                    Print(text),
+                    SetAttribute(Attribute::Reset)
+                )?;
+            } else {
+                queue!(stdout, Print(self.tasks.format_row(row, task)))?;
+            }
+        }
+
+        let status = match &self.mode {
+            Mode::Input(prompt, buffer) => {
+                let label = match prompt {
+                    Prompt::AddChild => "add sub-task",
+                    Prompt::AddSibling => "add task",
+                    Prompt::Edit => "edit",
+                    Prompt::Tag => "tags (+tag -tag)",
+                    Prompt::Filter => "filter",
+                };
+                format!("{}: {}", label, buffer)
+            }
+            Mode::Move(_) => {
+                "move: select new parent, enter to move here, r for top level, esc to cancel"
+                    .to_string()
+            }
+            Mode::Normal if !self.message.is_empty() => self.message.to_string(),
+            Mode::Normal => HELP.to_string(),
+        };
+        let status: String = status.chars().take(width as usize).collect();
+        queue!(stdout, cursor::MoveTo(0, height.saturating_sub(1)), Print(status))?;
+
+        stdout.flush()
+    }
+
+    fn handle_key(&mut self, key: KeyEvent) {
+        match std::mem::replace(&mut self.mode, Mode::Normal) {
+            Mode::Normal => {
+                self.message.clear();
+                self.handle_normal_key(key);
+            }
+            Mode::Input(prompt, buffer) => self.handle_input_key(key, prompt, buffer),
+            Mode::Move(id) => self.handle_move_key(key, id),
+        }
+    }
+
+    fn handle_navigation_key(&mut self, key: &KeyEvent) -> bool {
+        let page = terminal::size().map_or(10, |(_, h)| (h as usize).saturating_sub(2).max(1));
+        let last = self.rows.len().saturating_sub(1);
+
+        match key.code {
+            KeyCode::Up | KeyCode::Char('k') => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Down | KeyCode::Char('j') => self.cursor = (self.cursor + 1).min(last),
+            KeyCode::PageUp => self.cursor = self.cursor.saturating_sub(page),
+            KeyCode::PageDown => self.cursor = (self.cursor + page).min(last),
+            KeyCode::Home | KeyCode::Char('g') => self.cursor = 0,
+            KeyCode::End | KeyCode::Char('G') => self.cursor = last,
+            _ => return false,
+        }
+
+        true
+    }
+
+    fn handle_normal_key(&mut self, key: KeyEvent) {
+        if self.handle_navigation_key(&key) {
+            return;
+        }
+
+        let selected = self.selected_id();
+
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => self.quit = true,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => self.quit = true,
+            KeyCode::Left | KeyCode::Char('h') => {
+                if let Some(id) = selected {
+                    let row = &self.rows[self.cursor];
+                    if row.num_children > 0 && !self.collapsed.contains(&id) {
+                        self.collapsed.insert(id);
+                        self.refresh();
+                    } else if let Ok(Some(parent_id)) =
+                        self.tasks.get_task_ref(&id).map(|task| task.parent_id().clone())
+                    {
+                        self.select(&parent_id);
+                    }
+                }
+            }
+            KeyCode::Right | KeyCode::Char('l') => {
+                if let Some(id) = selected {
+                    if self.collapsed.remove(&id) {
+                        self.refresh();
+                    }
+                }
+            }
+            KeyCode::Char(' ') | KeyCode::Char('x') => {
+                if let Some(id) = selected {
+                    self.toggle_complete(&id);
+                }
+            }
+            KeyCode::Char('H') => {
+                self.hide_completed = !self.hide_completed;
+                self.refresh();
+            }
+            KeyCode::Char('a') => {
+                if selected.is_some() {
+                    self.mode = Mode::Input(Prompt::AddChild, String::new());
+                } else {
+                    self.mode = Mode::Input(Prompt::AddSibling, String::new());
+                }
+            }
+            KeyCode::Char('o') => self.mode = Mode::Input(Prompt::AddSibling, String::new()),
+            KeyCode::Char('e') => {
+                if let Some(id) = selected {
+                    if let Ok(task) = self.tasks.get_task_ref(&id) {
+                        self.mode = Mode::Input(Prompt::Edit, task.desc().to_string());
+                    }
+                }
+            }
+            KeyCode::Char('t') if selected.is_some() => {
+                self.mode = Mode::Input(Prompt::Tag, String::new());
+            }
+            KeyCode::Char('m') => {
+                if let Some(id) = selected {
+                    self.mode = Mode::Move(id);
+                }
+            }
+            KeyCode::Char('/') => self.mode = Mode::Input(Prompt::Filter, self.filter.to_string()),
+            _ => {}
+        }
+    }
+
+    fn handle_input_key(&mut self, key: KeyEvent, prompt: Prompt, mut buffer: String) {
+        match key.code {
+            KeyCode::Esc => {
+                if let Prompt::Filter = prompt {
+                    self.filter.clear();
+                    self.refresh();
+                }
+            }
+            KeyCode::Enter => self.submit(prompt, buffer.trim()),
+            KeyCode::Backspace => {
+                buffer.pop();
+                self.update_input(prompt, buffer);
+            }
+            KeyCode::Char(c) => {
+                buffer.push(c);
+                self.update_input(prompt, buffer);
+            }
+            _ => self.mode = Mode::Input(prompt, buffer),
+        }
+    }
+
+    fn update_input(&mut self, prompt: Prompt, buffer: String) {
+        // The filter is applied while typing
+        if let Prompt::Filter = prompt {
+            self.filter = buffer.to_string();
+            self.cursor = 0;
+            self.refresh();
+        }
+        self.mode = Mode::Input(prompt, buffer);
+    }
+
+    fn handle_move_key(&mut self, key: KeyEvent, id: String) {
+        if self.handle_navigation_key(&key) {
+            self.mode = Mode::Move(id);
+            return;
+        }
+
+        let result = match key.code {
+            KeyCode::Esc => return,
+            KeyCode::Enter => match self.selected_id() {
+                Some(parent_id) => {
+                    self.collapsed.remove(&parent_id);
+                    self.tasks.move_task(&id, Some(&parent_id))
+                }
+                None => return,
+            },
+            KeyCode::Char('r') => self.tasks.move_task(&id, None),
+            _ => {
+                self.mode = Mode::Move(id);
+                return;
+            }
+        };
+
+        match result {
+            Ok(()) => {
+                self.save();
+                self.select(&id);
+            }
+            Err(e) => self.message = format!("Error: {}", e),
+        }
+    }
+
+    fn submit(&mut self, prompt: Prompt, text: &str) {
+        let selected = self.selected_id();
+
+        match prompt {
+            Prompt::Filter => {}
+            Prompt::AddChild | Prompt::AddSibling => {
+                if text.is_empty() {
+                    return;
+                }
+
+                let parent_id = match (&prompt, &selected) {
+                    (Prompt::AddChild, Some(id)) => Some(id.to_string()),
+                    (_, Some(id)) => {
+                        self.tasks.get_task_ref(id).ok().and_then(|task| task.parent_id().clone())
+                    }
+                    (_, None) => None,
+                };
+
+                match self.tasks.add_task(parent_id.as_deref(), None, text) {
+                    Ok(id) => {
+                        if let Some(parent_id) = parent_id {
+                            self.collapsed.remove(&parent_id);
+                        }
+                        self.save();
+                        self.select(&id);
+                    }
+                    Err(e) => self.message = format!("Error: {}", e),
+                }
+            }
+            Prompt::Edit => {
+                if let (Some(id), false) = (selected, text.is_empty()) {
+                    if let Ok(task) = self.tasks.get_task(&id) {
+                        task.set_desc(text);
+                        self.save();
+                    }
+                }
+            }
+            Prompt::Tag => {
+                if let Some(id) = selected {
+                    if let Ok(task) = self.tasks.get_task(&id) {
+                        for tag in text.split_whitespace() {
+                            if let Some(tag) = tag.strip_prefix('-') {
+                                task.remove_tag(tag);
+                            } else {
+                                task.add_tag(tag.trim_start_matches('+'));
+                            }
+                        }
+                        self.save();
+                    }
+                }
+            }
+        }
+    }
+
+    fn toggle_complete(&mut self, id: &str) {
+        let completed = match self.tasks.get_task_ref(id) {
+            Ok(task) => task.is_completed(),
+            Err(_) => return,
+        };
+
+        let result = if completed {
+            self.tasks.get_task(id).map(|task| task.set_complete(false))
+        } else {
+            self.tasks.complete_task(id, false)
+        };
+
+        match result {
+            Ok(()) => self.save(),
+            Err(e) => self.message = format!("Error: {}", e),
+        }
+    }
+}